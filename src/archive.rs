@@ -0,0 +1,328 @@
+// Archiving old turns. Once a chat file grows beyond a number of turns or bytes,
+// its older turns are moved verbatim into a sidecar `<name>.archive.md` file,
+// leaving a pointer comment (and optionally a summary) behind.
+
+use std::fmt::{self, Write as FmtWrite};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use regex::Regex;
+use reqwest::Client;
+
+use crate::{
+    chat_sections, escape_markers, parse_chat_messages, parse_level, split_front_matter, write_atomically, ChatRequest,
+    ChatResponse, Markers, Message, Settings, API_URL,
+};
+
+// Heads the summary section written in place of archived turns
+const SUMMARY_NOTE: &str = "<!-- gchat: summary of archived turns -->";
+
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the following conversation between a user and Grok so it can stand in for it as context later. Keep the facts, decisions, code and file names that matter, and any open questions. Use concise bullet points and do not add commentary.";
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchivePolicy {
+    pub max_turns: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub summary: bool,
+}
+
+impl fmt::Display for ArchivePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limits: Vec<String> = self
+            .max_turns
+            .map(|turns| format!("{} turns", turns))
+            .into_iter()
+            .chain(self.max_bytes.map(|bytes| format!("{} bytes", bytes)))
+            .collect();
+        if limits.is_empty() {
+            return write!(f, "off");
+        }
+        write!(f, "beyond {}", limits.join(" or "))?;
+        if self.summary {
+            write!(f, ", with summary")?;
+        }
+        Ok(())
+    }
+}
+
+// Archive older turns of the chat file if it has outgrown the policy
+pub async fn archive_if_needed(chat_path: &Path, settings: &Settings, client: &Client, api_key: &str) -> io::Result<()> {
+    let policy = &settings.archive;
+    if policy.max_turns.is_none() && policy.max_bytes.is_none() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(chat_path)?;
+    let body = split_front_matter(&content, Some(&settings.markers)).map(|(_, _, body)| body).unwrap_or(&content);
+    let sections = chat_sections(body, &settings.markers);
+    let too_many = policy.max_turns.is_some_and(|max| sections.len() > max);
+    let too_big = policy.max_bytes.is_some_and(|max| content.len() as u64 > max);
+    if !too_many && !too_big {
+        return Ok(());
+    }
+
+    let Some(cut) = cut_point(&sections, body.len(), policy) else {
+        return Ok(());
+    };
+    // A summary from an earlier archive goes to the archive with the turns, but isn't one
+    let turns = (0..cut).filter(|&i| !is_summary(body, &sections[i])).count();
+
+    let first = sections[0].1.start;
+    let kept = sections[cut].1.start;
+    let archived = &body[first..kept];
+    let archive_path = archive_path_for(chat_path);
+    let archive_name = archive_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+    let summary = if policy.summary {
+        match summarize(archived, settings, client, api_key).await {
+            Ok(summary) => Some(summary),
+            Err(e) => {
+                println!("Warning: Failed to summarize archived turns: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Don't clobber edits made while the summary was being written
+    if fs::read_to_string(chat_path)? != content {
+        println!("Chat file changed while archiving; will try again after the next response.");
+        return Ok(());
+    }
+
+    // Append to the archive first, so nothing is lost if rewriting the chat file fails
+    let mut archive_content = match fs::read_to_string(&archive_path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if !archive_content.is_empty() && !archive_content.ends_with("\n\n") {
+        archive_content.push_str(if archive_content.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+    writeln!(
+        archive_content,
+        "<!-- gchat: archived from {} at {} -->",
+        chat_path.file_name().unwrap_or_default().to_string_lossy(),
+        Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+    )
+    .expect("Failed to write to String");
+    archive_content.push_str(archived);
+    write_atomically(&archive_path, &archive_content)?;
+
+    let live = rewrite_chat(&content, first..kept, turns, &archive_name, summary.as_deref(), &settings.markers);
+    write_atomically(chat_path, &live)?;
+
+    println!("Archived {} older turns to {}", turns, archive_path.display());
+    Ok(())
+}
+
+// The section to cut a chat's sections at, archiving those before it. Always cut at a
+// user prompt, and never archive the latest exchange: the prompt just answered, its
+// response and the new prompt after it.
+fn cut_point(sections: &[(&'static str, Range<usize>)], body_len: usize, policy: &ArchivePolicy) -> Option<usize> {
+    let user_turns: Vec<usize> = (0..sections.len()).filter(|&i| sections[i].0 == "user").collect();
+    let latest = *user_turns.iter().rev().nth(1).filter(|&&i| i > 0)?;
+    // Archive down to half of each limit, so it doesn't happen again on the next turn
+    let keep_turns = policy.max_turns.map(|max| (max / 2).max(3));
+    let keep_bytes = policy.max_bytes.map(|max| max / 2);
+    let cut = user_turns
+        .iter()
+        .copied()
+        .filter(|&i| i > 0 && i <= latest)
+        .find(|&i| {
+            keep_turns.is_none_or(|turns| sections.len() - i <= turns)
+                && keep_bytes.is_none_or(|bytes| (body_len - sections[i].1.start) as u64 <= bytes)
+        })
+        .unwrap_or(latest);
+    Some(cut)
+}
+
+fn is_summary(body: &str, section: &(&'static str, Range<usize>)) -> bool {
+    section.0 == "assistant" && body[section.1.clone()].lines().nth(1).is_some_and(|line| line.trim() == SUMMARY_NOTE)
+}
+
+// The chat file with the `archived` part of its body (after any front matter) taken
+// out: front matter, any notes before the first turn (with the pointer updated to
+// count `turns` more), the optional summary, then the turns kept. The summary is
+// Grok's text, so it's kept as a response: placeholders and directives it quotes are
+// never expanded.
+fn rewrite_chat(
+    content: &str,
+    archived: Range<usize>,
+    turns: usize,
+    archive_name: &str,
+    summary: Option<&str>,
+    markers: &Markers,
+) -> String {
+    let body = split_front_matter(content, Some(markers)).map(|(_, _, body)| body).unwrap_or(content);
+    let pointer = Regex::new(r"^<!-- gchat: (\d+) earlier turns? archived in .* -->$").unwrap();
+    let mut total = turns;
+    let mut notes = String::new();
+    for line in body[..archived.start].lines() {
+        match pointer.captures(line.trim()) {
+            Some(cap) => total += cap[1].parse::<usize>().unwrap_or(0),
+            None => writeln!(notes, "{}", line).expect("Failed to write to String"),
+        }
+    }
+    let mut live = content[..content.len() - body.len()].to_string();
+    if !notes.trim().is_empty() {
+        writeln!(live, "{}\n", notes.trim_end()).expect("Failed to write to String");
+    }
+    writeln!(live, "<!-- gchat: {} earlier turns archived in {} -->\n", total, archive_name).expect("Failed to write to String");
+    if let Some(summary) = summary {
+        write!(
+            live,
+            "{}\n{}\nSummary of the earlier conversation (archived in {}):\n\n{}\n\n",
+            markers.grok,
+            SUMMARY_NOTE,
+            archive_name,
+            escape_markers(summary.trim(), markers)
+        )
+        .expect("Failed to write to String");
+    }
+    live.push_str(&body[archived.end..]);
+    live
+}
+
+// `gchat.md` archives to `gchat.archive.md`
+fn archive_path_for(chat_path: &Path) -> PathBuf {
+    let stem = chat_path.file_stem().and_then(|s| s.to_str()).unwrap_or("gchat");
+    chat_path.with_file_name(format!("{}.archive.md", stem))
+}
+
+// Ask Grok for a summary of the turns being archived
+async fn summarize(archived: &str, settings: &Settings, client: &Client, api_key: &str) -> io::Result<String> {
+    let mut transcript = String::new();
+    for msg in parse_chat_messages(archived, &settings.markers) {
+        let speaker = if msg.role == "user" { "User" } else { "Grok" };
+        write!(transcript, "{}:\n{}\n\n", speaker, msg.content).expect("Failed to write to String");
+    }
+
+    let req = ChatRequest {
+        model: settings.model.clone(),
+        messages: vec![
+            Message {
+                role: "system".to_string(),
+                content: SUMMARY_INSTRUCTIONS.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+            },
+        ],
+        temperature: 0.2,
+        max_tokens: parse_level(settings.default_level),
+    };
+
+    println!("Grok is summarizing archived turns...");
+    let resp = client
+        .post(API_URL)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&req)
+        .send()
+        .await
+        .map_err(|e| io::Error::other(format!("Request error: {:?}", e)))?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let err_body = resp.text().await.unwrap_or_default();
+        return Err(io::Error::other(format!("API error: {} - Body: {}", status, err_body)));
+    }
+    let chat_resp: ChatResponse = resp.json().await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    chat_resp
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty summary response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain() -> Markers {
+        Markers::from_style("plain").unwrap()
+    }
+
+    // `exchanges` answered prompts, then the new prompt
+    fn chat(exchanges: usize) -> String {
+        let mut body: String = (1..=exchanges).map(|i| format!("USER PROMPT:\nq{}\n\nGROK RESPONSE:\na{}\n\n", i, i)).collect();
+        body.push_str("USER PROMPT:\nnext\n");
+        body
+    }
+
+    fn cut(body: &str, max_turns: Option<usize>, max_bytes: Option<u64>) -> Option<usize> {
+        let policy = ArchivePolicy { max_turns, max_bytes, summary: false };
+        cut_point(&chat_sections(body, &plain()), body.len(), &policy)
+    }
+
+    #[test]
+    fn cuts_at_a_prompt_down_to_half_the_turn_limit() {
+        let body = chat(10);
+        let sections = chat_sections(&body, &plain());
+        assert_eq!(sections.len(), 21);
+        let at = cut(&body, Some(10), None).unwrap();
+        assert_eq!((at, sections[at].0), (16, "user"));
+        // An odd half keeps one turn fewer rather than cutting between a prompt and its response
+        assert_eq!(cut(&body, Some(12), None), Some(16));
+        assert_eq!(cut(&body, Some(14), None), Some(14));
+    }
+
+    #[test]
+    fn cuts_down_to_half_the_byte_limit() {
+        let body = chat(10);
+        let sections = chat_sections(&body, &plain());
+        let at = cut(&body, None, Some(200)).unwrap();
+        assert!((body.len() - sections[at].1.start) as u64 <= 100);
+        assert!((body.len() - sections[at - 2].1.start) as u64 > 100);
+        assert_eq!(sections[at].0, "user");
+    }
+
+    #[test]
+    fn never_archives_the_latest_exchange() {
+        let body = chat(10);
+        // The prompt just answered, its response and the new prompt stay, however low the limits
+        assert_eq!(cut(&body, Some(1), None), Some(18));
+        assert_eq!(cut(&body, None, Some(1)), Some(18));
+        assert_eq!(cut(&chat(1), Some(1), Some(1)), None);
+        assert_eq!(cut(&chat(2), Some(1), None), Some(2));
+    }
+
+    #[test]
+    fn pointer_counts_turns_not_summaries() {
+        let markers = plain();
+        let content = format!(
+            "---\nmodel: grok-4\n---\nA note.\n<!-- gchat: 2 earlier turns archived in chat.archive.md -->\n\nGROK RESPONSE:\n{}\nSummary of the earlier conversation (archived in chat.archive.md):\n\nOld summary\n\n{}",
+            SUMMARY_NOTE,
+            chat(3)
+        );
+        let body = split_front_matter(&content, Some(&markers)).unwrap().2;
+        let sections = chat_sections(body, &markers);
+        assert!(is_summary(body, &sections[0]));
+        assert!(!is_summary(body, &sections[1]));
+
+        let at = cut(body, Some(4), None).unwrap();
+        assert_eq!(at, 5);
+        let turns = (0..at).filter(|&i| !is_summary(body, &sections[i])).count();
+        assert_eq!(turns, 4);
+
+        let live = rewrite_chat(&content, sections[0].1.start..sections[at].1.start, turns, "chat.archive.md", Some("New summary"), &markers);
+        assert_eq!(
+            live,
+            format!(
+                "---\nmodel: grok-4\n---\nA note.\n\n<!-- gchat: 6 earlier turns archived in chat.archive.md -->\n\nGROK RESPONSE:\n{}\nSummary of the earlier conversation (archived in chat.archive.md):\n\nNew summary\n\nUSER PROMPT:\nq3\n\nGROK RESPONSE:\na3\n\nUSER PROMPT:\nnext\n",
+                SUMMARY_NOTE
+            )
+        );
+        // And once more, with no summary this time
+        let body = split_front_matter(&live, Some(&markers)).unwrap().2;
+        let sections = chat_sections(body, &markers);
+        let again = rewrite_chat(&live, sections[0].1.start..sections[1].1.start, 0, "chat.archive.md", None, &markers);
+        assert!(again.contains("<!-- gchat: 6 earlier turns archived in chat.archive.md -->\n\nUSER PROMPT:\nq3\n"));
+    }
+}
@@ -0,0 +1,202 @@
+// Including part of a file: `@f:path#L300-420` for a line range, `@f:path#tail=200`
+// for the last lines, and `@f:path.rs::name` for a Rust item (fn, struct, enum,
+// trait, impl, mod...), a method in an impl or trait (`Type::method`), or an item
+// in an inline module (`module::name`).
+
+use std::io;
+
+use proc_macro2::Span;
+use syn::spanned::Spanned;
+use syn::{ImplItem, Item, TraitItem, Type};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    // 1-based, inclusive; no end means to the end of the file
+    Lines(usize, Option<usize>),
+    Tail(usize),
+    Symbol(String),
+}
+
+// Split a selector off an `@f:` path, if it has one
+pub fn split_selector(path: &str) -> io::Result<(&str, Option<Selector>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    if let Some((file, symbol)) = path.split_once("::") {
+        if symbol.is_empty() || !symbol.split("::").all(|s| !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')) {
+            return Err(invalid(format!("Invalid symbol '{}'", symbol)));
+        }
+        return Ok((file, Some(Selector::Symbol(symbol.to_string()))));
+    }
+
+    let Some((file, fragment)) = path.rsplit_once('#') else {
+        return Ok((path, None));
+    };
+    if let Some(count) = fragment.strip_prefix("tail=") {
+        let count = count.parse().map_err(|_| invalid(format!("Invalid tail '{}': expected tail=N", fragment)))?;
+        return Ok((file, Some(Selector::Tail(count))));
+    }
+    if let Some(range) = fragment.strip_prefix('L') {
+        let number = |s: &str| s.parse::<usize>().map_err(|_| invalid(format!("Invalid line range '{}': expected L300-420", fragment)));
+        let (start, end) = match range.split_once('-') {
+            Some((start, "")) => (number(start)?, None),
+            Some((start, end)) => (number(start)?, Some(number(end.trim_start_matches('L'))?)),
+            None => (number(range)?, Some(number(range)?)),
+        };
+        if start == 0 || end.is_some_and(|end| end < start) {
+            return Err(invalid(format!("Invalid line range '{}'", fragment)));
+        }
+        return Ok((file, Some(Selector::Lines(start, end))));
+    }
+    // Not a selector: `#` is part of the file name
+    Ok((path, None))
+}
+
+// The selected parts of a file's content, each with a label describing it and
+// the line number it starts at
+pub fn select(content: &str, selector: &Selector) -> io::Result<Vec<(String, String, usize)>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let total = lines.len();
+    match selector {
+        Selector::Lines(start, end) => {
+            if *start > total {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Line {} is past the end of the file ({} lines)", start, total),
+                ));
+            }
+            let end = end.unwrap_or(total).min(total);
+            Ok(vec![(lines[start - 1..end].join("\n"), format!("lines {}-{} of {}", start, end, total), *start)])
+        }
+        Selector::Tail(count) => {
+            let start = total.saturating_sub(*count);
+            let label = format!("last {} lines, {}-{} of {}", total - start, start + 1, total, total);
+            Ok(vec![(lines[start..].join("\n"), label, start + 1)])
+        }
+        Selector::Symbol(symbol) => {
+            let file = syn::parse_file(content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse as Rust: {}", e)))?;
+            let path: Vec<&str> = symbol.split("::").collect();
+            let mut found = Vec::new();
+            find_items(&file.items, &path, &mut found);
+            if found.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("No item named '{}'", symbol)));
+            }
+            Ok(found
+                .into_iter()
+                .map(|(kind, span)| {
+                    let (start, end) = (span.start().line, span.end().line.min(total));
+                    (lines[start - 1..end].join("\n"), format!("{} {}, lines {}-{} of {}", kind, symbol, start, end, total), start)
+                })
+                .collect())
+        }
+    }
+}
+
+// Collect the items matching a `::` path, with a word for what each one is
+fn find_items(items: &[Item], path: &[&str], found: &mut Vec<(&'static str, Span)>) {
+    let (name, rest) = (path[0], &path[1..]);
+    for item in items {
+        match item {
+            Item::Impl(imp) if type_name(&imp.self_ty).as_deref() == Some(name) => {
+                if rest.is_empty() {
+                    found.push(("impl", item.span()));
+                } else if rest.len() == 1 {
+                    for impl_item in &imp.items {
+                        if let ImplItem::Fn(method) = impl_item {
+                            if method.sig.ident == rest[0] {
+                                found.push(("fn", impl_item.span()));
+                            }
+                        }
+                    }
+                }
+            }
+            Item::Trait(tr) if tr.ident == name && rest.len() == 1 => {
+                for trait_item in &tr.items {
+                    if let TraitItem::Fn(method) = trait_item {
+                        if method.sig.ident == rest[0] {
+                            found.push(("fn", trait_item.span()));
+                        }
+                    }
+                }
+            }
+            Item::Mod(module) if module.ident == name && !rest.is_empty() => {
+                if let Some((_, items)) = &module.content {
+                    find_items(items, rest, found);
+                }
+            }
+            _ if rest.is_empty() => {
+                if let Some(kind) = item_kind(item, name) {
+                    found.push((kind, item.span()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// What an item is, if it's named `name`
+fn item_kind(item: &Item, name: &str) -> Option<&'static str> {
+    let (ident, kind) = match item {
+        Item::Fn(f) => (&f.sig.ident, "fn"),
+        Item::Struct(s) => (&s.ident, "struct"),
+        Item::Enum(e) => (&e.ident, "enum"),
+        Item::Union(u) => (&u.ident, "union"),
+        Item::Trait(t) => (&t.ident, "trait"),
+        Item::Type(t) => (&t.ident, "type"),
+        Item::Const(c) => (&c.ident, "const"),
+        Item::Static(s) => (&s.ident, "static"),
+        Item::Mod(m) => (&m.ident, "mod"),
+        Item::Macro(m) => (m.ident.as_ref()?, "macro"),
+        _ => return None,
+    };
+    (ident == name).then_some(kind)
+}
+
+// The last segment of an impl's self type, e.g. `Settings` for `impl Foo for crate::Settings`
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        assert_eq!(split_selector("src/main.rs").unwrap(), ("src/main.rs", None));
+        assert_eq!(split_selector("a.rs#L3-7").unwrap(), ("a.rs", Some(Selector::Lines(3, Some(7)))));
+        assert_eq!(split_selector("a.rs#L3-L7").unwrap(), ("a.rs", Some(Selector::Lines(3, Some(7)))));
+        assert_eq!(split_selector("a.rs#L3").unwrap(), ("a.rs", Some(Selector::Lines(3, Some(3)))));
+        assert_eq!(split_selector("a.rs#L3-").unwrap(), ("a.rs", Some(Selector::Lines(3, None))));
+        assert_eq!(split_selector("a.rs#tail=20").unwrap(), ("a.rs", Some(Selector::Tail(20))));
+        assert_eq!(
+            split_selector("a.rs::Settings::report").unwrap(),
+            ("a.rs", Some(Selector::Symbol("Settings::report".to_string())))
+        );
+        // `#` that isn't a selector is part of the name
+        assert_eq!(split_selector("notes#1.md").unwrap(), ("notes#1.md", None));
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for path in ["a.rs#L0", "a.rs#L7-3", "a.rs#Lx", "a.rs#tail=x", "a.rs::", "a.rs::a::", "a.rs::a-b"] {
+            assert!(split_selector(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn select_lines_and_items() {
+        let source = "fn a() {}\n\nstruct S;\n\nimpl S {\n    fn m(&self) {}\n}\n";
+        let parts = select(source, &Selector::Lines(3, Some(99))).unwrap();
+        assert_eq!(parts, [("struct S;\n\nimpl S {\n    fn m(&self) {}\n}".to_string(), "lines 3-7 of 7".to_string(), 3)]);
+        let parts = select(source, &Selector::Tail(2)).unwrap();
+        assert_eq!(parts[0].1, "last 2 lines, 6-7 of 7");
+        let parts = select(source, &Selector::Symbol("S::m".to_string())).unwrap();
+        assert_eq!(parts, [("    fn m(&self) {}".to_string(), "fn S::m, lines 6-6 of 7".to_string(), 6)]);
+        assert!(select(source, &Selector::Lines(8, None)).is_err());
+        assert!(select(source, &Selector::Symbol("missing".to_string())).is_err());
+    }
+}
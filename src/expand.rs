@@ -0,0 +1,954 @@
+// Placeholder expansion for user prompts: `@f:` file contents, `@d:` directory
+// trees, `@outline:` Rust API outlines, `@git:` repository output, `@sh:` command
+// output and `@url:` pages. Directories and globs are walked with the same rules as git, honouring
+// `.gitignore`, `.ignore` and `.gchatignore` files and skipping hidden entries.
+// Every file goes through `Expander::read_file`, which detects binaries and turns
+// unreadable files into a note instead of failing the whole placeholder. Budgets
+// cap how much of each file, and of each placeholder, is included.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write as FmtWrite};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+use ignore::WalkBuilder;
+use regex::Regex;
+use similar::TextDiff;
+
+use crate::excerpt;
+use crate::git::{self, GitInclude};
+use crate::outline;
+use crate::render::Rendering;
+use crate::shell::ShellPolicy;
+use crate::snapshot::Snapshots;
+use crate::web::{self, Page};
+use crate::{Settings, REQUESTED_FILES_MARKER};
+
+const IGNORE_FILE: &str = ".gchatignore";
+// Like git, a file with a NUL byte near its start is treated as binary
+const BINARY_SNIFF_LEN: usize = 8000;
+// A rough average, for budgets given in tokens
+const BYTES_PER_TOKEN: u64 = 4;
+// Files that would get less than this much of a placeholder's remaining budget
+// are left out rather than cut to a sliver
+const MIN_EXCERPT_BYTES: u64 = 512;
+
+// Limits on how much an include may add to a prompt, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub per_file: Option<u64>,
+    pub per_placeholder: Option<u64>,
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limits: Vec<String> = self
+            .per_file
+            .map(|bytes| format!("{} bytes per file", bytes))
+            .into_iter()
+            .chain(self.per_placeholder.map(|bytes| format!("{} bytes per placeholder", bytes)))
+            .collect();
+        if limits.is_empty() {
+            return write!(f, "unlimited");
+        }
+        write!(f, "{}", limits.join(", "))
+    }
+}
+
+// Parse a size such as "40000", "40k", "2m" (bytes) or "10kt", "8000t" (tokens,
+// estimated at four bytes each)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_lowercase();
+    let (number, tokens) = match lower.strip_suffix('t') {
+        Some(number) => (number, true),
+        None => (lower.strip_suffix('b').unwrap_or(&lower), false),
+    };
+    let (number, multiplier) = match number.strip_suffix('k') {
+        Some(number) => (number, 1_000),
+        None => match number.strip_suffix('m') {
+            Some(number) => (number, 1_000_000),
+            None => (number, 1),
+        },
+    };
+    let amount: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}': expected e.g. 40000, 40k, 2m or 10kt (tokens)", value))?;
+    if amount < 0.0 {
+        return Err(format!("Invalid size '{}': must not be negative", value));
+    }
+    let bytes = (amount * multiplier as f64) as u64;
+    Ok(if tokens { bytes * BYTES_PER_TOKEN } else { bytes })
+}
+
+// A file's content as far as a prompt is concerned
+enum FileContent {
+    Text(String),
+    Binary(u64),
+}
+
+// What happened to the files of one placeholder, for its summary
+#[derive(Default)]
+struct ExpansionStats {
+    included: usize,
+    binary: usize,
+    unreadable: Vec<String>,
+    // Files cut down to fit the budget: path, original and included bytes
+    trimmed: Vec<(String, usize, usize)>,
+    // Files left out once the placeholder's budget ran out: path and bytes
+    omitted: Vec<(String, usize)>,
+    // Files already sent in an earlier prompt, unchanged or as a diff
+    unchanged: Vec<String>,
+    diffed: Vec<String>,
+    // Content bytes included so far, against the placeholder's budget
+    used: u64,
+}
+
+pub struct Expander {
+    budget: Budget,
+    rendering: Rendering,
+    shell: ShellPolicy,
+    // Pages for `@url:` placeholders, fetched beforehand by `fetch_pages`
+    pages: HashMap<String, Result<Page, String>>,
+    // With `dedup`, the content last sent for each file (or part of one), by label
+    dedup: bool,
+    sent: HashMap<String, String>,
+    // The store kept command output goes in, and in snapshot mode file reads go through
+    snapshots: Option<Snapshots>,
+    stats: ExpansionStats,
+}
+
+impl Expander {
+    pub fn new(settings: &Settings) -> Expander {
+        Expander {
+            budget: settings.budget,
+            rendering: settings.rendering,
+            shell: settings.shell.clone(),
+            pages: HashMap::new(),
+            dedup: settings.dedup_includes,
+            sent: HashMap::new(),
+            snapshots: None,
+            stats: ExpansionStats::default(),
+        }
+    }
+
+    // Keep each prompt's command output, and in snapshot mode the files it was first sent with, in a store
+    pub fn use_snapshots(&mut self, snapshots: Snapshots) {
+        self.snapshots = Some(snapshots);
+    }
+
+    // Hand back the store once every prompt has been expanded, to be saved when the
+    // prompts have been sent
+    pub fn finish(&mut self) -> Option<Snapshots> {
+        let snapshots = self.snapshots.take()?;
+        if snapshots.frozen_reads > 0 || snapshots.new_reads > 0 {
+            println!(
+                "Snapshots: {} file read{} from earlier sends, {} to freeze once sent",
+                snapshots.frozen_reads,
+                if snapshots.frozen_reads == 1 { "" } else { "s" },
+                snapshots.new_reads
+            );
+        }
+        Some(snapshots)
+    }
+
+    // Fetch the pages the `@url:` placeholders in these prompts refer to
+    pub async fn fetch_pages<'a>(&mut self, client: &reqwest::Client, prompts: impl Iterator<Item = &'a str>) {
+        self.pages = web::fetch_pages(client, prompts).await;
+    }
+
+    // Expand every `@f:`/`@d:`/`@git:`/`@sh:`/`@url:`/`@outline:` placeholder in a
+    // prompt. A placeholder that can't be expanded at all is left as written, with a
+    // warning. `@sh` commands only run in the prompt being sent (`sending`); earlier
+    // prompts resend the output kept from when they were sent.
+    pub fn expand_placeholders(&mut self, text: &str, sending: bool) -> io::Result<String> {
+        let re = Regex::new(&format!(
+            r#"@f\s*:(\S+)|@d\s*:(\S+)|@git\s*:(\S+)|@sh\s*:(?:"([^"]*)"(\?\S+)?|(\S+))|{}|@outline\s*:(\S+)"#,
+            web::URL_PATTERN
+        ))
+        .unwrap();
+        // Commands never run from placeholders the model asked for
+        let requested_start = text.find(REQUESTED_FILES_MARKER).unwrap_or(text.len());
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.begin_turn(text, sending);
+        }
+        let mut result = String::new();
+        let mut last_end = 0;
+
+        for cap in re.captures_iter(text) {
+            let match_range = cap.get(0).unwrap();
+            let placeholder = match_range.as_str();
+            let match_start = match_range.start();
+            result.push_str(&text[last_end..match_start]);
+
+            if let Some(file_path) = cap.get(1) {
+                let path_str = file_path.as_str();
+                self.stats = ExpansionStats::default();
+                match self.expand_file_path(path_str) {
+                    Ok(expanded) => {
+                        result.push_str(&expanded);
+                        result.push_str(&self.budget_note());
+                        self.print_summary(placeholder);
+                    }
+                    Err(e) => {
+                        println!("Warning: Failed to expand file placeholder '{}' : {} (path: {})", placeholder, e, path_str);
+                        result.push_str(placeholder);
+                    }
+                }
+            } else if let Some(dir_path) = cap.get(2) {
+                let path_str = dir_path.as_str();
+                match split_options(path_str, self.budget).and_then(|(path, budget)| expand_dir_tree(path, budget, &self.rendering)) {
+                    Ok(expanded) => result.push_str(&expanded),
+                    Err(e) => {
+                        println!("Warning: Failed to expand directory placeholder '{}' : {} (path: {})", placeholder, e, path_str);
+                        result.push_str(placeholder);
+                    }
+                }
+            } else if let Some(spec) = cap.get(3) {
+                self.stats = ExpansionStats::default();
+                match self.expand_git(spec.as_str()) {
+                    Ok(expanded) => {
+                        result.push_str(&expanded);
+                        result.push_str(&self.budget_note());
+                        self.print_summary(placeholder);
+                    }
+                    Err(e) => {
+                        println!("Warning: Failed to expand git placeholder '{}' : {}", placeholder, e);
+                        result.push_str(placeholder);
+                    }
+                }
+            } else if let Some(command) = cap.get(4).or(cap.get(6)) {
+                if match_start >= requested_start {
+                    println!("Warning: Not running '{}' : it's in files requested by Grok", placeholder);
+                    result.push_str(placeholder);
+                } else {
+                    self.stats = ExpansionStats::default();
+                    // Options follow the closing quote of a quoted command
+                    let options = cap.get(5).map_or(command.as_str(), |m| m.as_str());
+                    match self.expand_command(command.as_str(), options, sending) {
+                        Ok(expanded) => {
+                            result.push_str(&expanded);
+                            result.push_str(&self.budget_note());
+                            self.print_summary(placeholder);
+                        }
+                        Err(e) => {
+                            println!("Warning: Failed to expand shell placeholder '{}' : {}", placeholder, e);
+                            result.push_str(placeholder);
+                        }
+                    }
+                }
+            } else if let Some(url) = cap.get(7) {
+                self.stats = ExpansionStats::default();
+                match self.expand_url(url.as_str()) {
+                    Ok(expanded) => {
+                        result.push_str(&expanded);
+                        result.push_str(&self.budget_note());
+                        self.print_summary(placeholder);
+                    }
+                    Err(e) => {
+                        println!("Warning: Failed to expand URL placeholder '{}' : {}", placeholder, e);
+                        result.push_str(placeholder);
+                    }
+                }
+            } else if let Some(path) = cap.get(8) {
+                self.stats = ExpansionStats::default();
+                match self.expand_outline(path.as_str()) {
+                    Ok(expanded) => {
+                        result.push_str(&expanded);
+                        result.push_str(&self.budget_note());
+                        self.print_summary(placeholder);
+                    }
+                    Err(e) => {
+                        println!("Warning: Failed to expand outline placeholder '{}' : {}", placeholder, e);
+                        result.push_str(placeholder);
+                    }
+                }
+            }
+
+            last_end = match_range.end();
+        }
+
+        result.push_str(&text[last_end..]);
+        Ok(result)
+    }
+
+    fn expand_file_path(&mut self, path_str: &str) -> io::Result<String> {
+        // Inline options apply to this placeholder only
+        let (path_str, budget) = split_options(path_str, self.budget)?;
+        let default = std::mem::replace(&mut self.budget, budget);
+        let result = self.expand_files(path_str);
+        self.budget = default;
+        result
+    }
+
+    fn expand_git(&mut self, spec: &str) -> io::Result<String> {
+        let (spec, budget) = split_options(spec, self.budget)?;
+        let include = GitInclude::parse(spec)?;
+        let command = include.to_string();
+        let output = include.run()?;
+
+        let default = std::mem::replace(&mut self.budget, budget);
+        let text = self.fit(command.clone(), &output);
+        self.budget = default;
+        Ok(match text {
+            Some(text) => self.rendering.output(&command, include.language(), &text),
+            None => self.rendering.output(&command, "", &format!("(omitted, include budget used up; {} bytes)", output.len())),
+        })
+    }
+
+    // `options` is the command itself if it wasn't quoted, or what follows the quotes
+    fn expand_command(&mut self, command: &str, options: &str, sending: bool) -> io::Result<String> {
+        let (rest, budget) = split_options(options, self.budget)?;
+        let command = if options == command { rest } else { command }.trim().to_string();
+        let output = self.command_output(&command, sending)?;
+
+        let default = std::mem::replace(&mut self.budget, budget);
+        let text = self.fit(command.clone(), &output);
+        self.budget = default;
+        Ok(match text {
+            Some(text) => self.rendering.output(&command, "", &text),
+            None => self.rendering.output(&command, "", &format!("(omitted, include budget used up; {} bytes)", output.len())),
+        })
+    }
+
+    // Run a command in the prompt being sent, keeping its output; an earlier prompt
+    // gets the output kept from when it was sent, as commands can have side effects
+    fn command_output(&mut self, command: &str, sending: bool) -> io::Result<String> {
+        if !sending {
+            return self.snapshots.as_ref().and_then(|s| s.command_output(command)).ok_or_else(|| {
+                io::Error::other("not run again, and no output was kept from when this prompt was sent")
+            });
+        }
+        let output = self.shell.run(command)?;
+        if let Some(snapshots) = &mut self.snapshots {
+            if let Err(e) = snapshots.keep_command_output(command, &output) {
+                println!("Warning: Failed to keep the output of '{}': {}", command, e);
+            }
+        }
+        Ok(output)
+    }
+
+    fn expand_url(&mut self, url: &str) -> io::Result<String> {
+        let page = match self.pages.get(url) {
+            Some(Ok(page)) => page.clone(),
+            Some(Err(e)) => return Err(io::Error::other(e.clone())),
+            None => return Err(io::Error::other("Not fetched")),
+        };
+        Ok(match self.fit(url.to_string(), &page.text) {
+            Some(text) => self.rendering.page(url, page.language, &text),
+            None => self.rendering.page(url, "", &format!("(omitted, include budget used up; {} bytes)", page.text.len())),
+        })
+    }
+
+    // Outlines of a Rust file, or of every Rust file in a directory
+    fn expand_outline(&mut self, path_str: &str) -> io::Result<String> {
+        let (path_str, budget) = split_options(path_str, self.budget)?;
+        let path = Path::new(path_str);
+        let files: Vec<PathBuf> = if path.is_dir() {
+            walk(path)
+                .filter(|(p, is_dir)| !is_dir && p.extension().is_some_and(|e| e == "rs"))
+                .map(|(p, _)| p)
+                .collect()
+        } else if path.is_file() {
+            vec![path.to_path_buf()]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "File or directory not found"));
+        };
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No Rust files found"));
+        }
+
+        let default = std::mem::replace(&mut self.budget, budget);
+        let mut output = String::new();
+        for file in files {
+            self.write_outline(&mut output, &file);
+        }
+        self.budget = default;
+        Ok(output)
+    }
+
+    fn write_outline(&mut self, output: &mut String, path: &Path) {
+        let outline = match self.read_file(path) {
+            Ok(FileContent::Text(source)) => outline::outline(&source),
+            Ok(FileContent::Binary(len)) => {
+                self.stats.binary += 1;
+                output.push_str(&self.rendering.note(path, &format!("binary, {} bytes", len)));
+                return;
+            }
+            Err(e) => Err(e),
+        };
+        match outline {
+            // Nothing public
+            Ok(outline) if outline.is_empty() => {}
+            Ok(outline) => match self.fit(format!("{} (outline)", path.display()), &outline) {
+                Some(text) => output.push_str(&self.rendering.file(path, Some("outline"), &text)),
+                None => output.push_str(&self.rendering.note(path, &format!("outline omitted, include budget used up; {} bytes", outline.len()))),
+            },
+            Err(e) => {
+                self.stats.unreadable.push(format!("{} ({})", path.display(), e));
+                output.push_str(&self.rendering.note(path, &format!("unreadable: {}", e)));
+            }
+        }
+    }
+
+    fn expand_files(&mut self, path_str: &str) -> io::Result<String> {
+        if let Some((rev, file)) = git::split_revision(path_str) {
+            return self.expand_revision(rev, file);
+        }
+        let (path_str, selector) = excerpt::split_selector(path_str)?;
+        let path = Path::new(path_str);
+        let mut output = String::new();
+
+        if let Some(selector) = selector {
+            // Part of a single file
+            if !path.is_file() && !self.is_frozen(path) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "Line ranges, tails and symbols need a single existing file"));
+            }
+            let content = match self.read_file(path)? {
+                FileContent::Text(content) => content,
+                FileContent::Binary(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "File is binary")),
+            };
+            for (part, label, first_line) in excerpt::select(&content, &selector)? {
+                self.write_text(&mut output, path, Some(&label), &part, first_line);
+            }
+        } else if path_str.contains('*') || path_str.contains('?') || path_str.contains('[') {
+            // Glob
+            let files = glob_files(path_str)?;
+            if files.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No files matched the pattern"));
+            }
+            for p in files {
+                self.write_file(&mut output, &p);
+            }
+        } else if path.is_dir() {
+            // Directory recurse
+            let files: Vec<PathBuf> = walk(path).filter(|(_, is_dir)| !is_dir).map(|(p, _)| p).collect();
+            if files.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No files found in directory"));
+            }
+            for p in files {
+                self.write_file(&mut output, &p);
+            }
+        } else {
+            // Single file (which needn't still exist if a snapshot has it)
+            if !path.exists() && !self.is_frozen(path) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
+            }
+            if path.exists() && !path.is_file() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is not a file"));
+            }
+            match self.read_file(path)? {
+                FileContent::Text(content) => self.write_text(&mut output, path, None, &content, 1),
+                FileContent::Binary(len) => output.push_str(&self.rendering.note(path, &format!("binary, {} bytes", len))),
+            }
+        }
+
+        Ok(output)
+    }
+
+    // A file (or part of one) as of a git revision, from `@f:<rev>:path`
+    fn expand_revision(&mut self, rev: &str, path_str: &str) -> io::Result<String> {
+        let (path_str, selector) = excerpt::split_selector(path_str)?;
+        let path = Path::new(path_str);
+        let mut output = String::new();
+        let content = match decode(git::file_at(rev, path_str)?) {
+            FileContent::Text(content) => content,
+            FileContent::Binary(len) => {
+                output.push_str(&self.rendering.note(path, &format!("at {}, binary, {} bytes", rev, len)));
+                return Ok(output);
+            }
+        };
+        match selector {
+            Some(selector) => {
+                for (part, label, first_line) in excerpt::select(&content, &selector)? {
+                    self.write_text(&mut output, path, Some(&format!("at {}, {}", rev, label)), &part, first_line);
+                }
+            }
+            None => self.write_text(&mut output, path, Some(&format!("at {}", rev)), &content, 1),
+        }
+        Ok(output)
+    }
+
+    // Include one file of a directory or glob, noting rather than failing on binary
+    // and unreadable files
+    fn write_file(&mut self, output: &mut String, path: &Path) {
+        match self.read_file(path) {
+            Ok(FileContent::Text(content)) => self.write_text(output, path, None, &content, 1),
+            Ok(FileContent::Binary(len)) => {
+                self.stats.binary += 1;
+                output.push_str(&self.rendering.note(path, &format!("binary, {} bytes", len)));
+            }
+            Err(e) => {
+                self.stats.unreadable.push(format!("{} ({})", path.display(), e));
+                output.push_str(&self.rendering.note(path, &format!("unreadable: {}", e)));
+            }
+        }
+    }
+
+    // Include a file's text (or the part of it `detail` describes, starting at line
+    // `first_line`), cut down to what's left of the budget
+    fn write_text(&mut self, output: &mut String, path: &Path, detail: Option<&str>, content: &str, first_line: usize) {
+        let label = match detail {
+            Some(detail) => format!("{} ({})", path.display(), detail),
+            None => path.display().to_string(),
+        };
+        // `./src/main.rs` and `src/main.rs` are the same file
+        let key = label.strip_prefix("./").unwrap_or(&label).to_string();
+        if self.dedup && self.write_repeat(output, path, detail, &label, &key, content) {
+            return;
+        }
+        let numbered = self.rendering.number(content, first_line);
+        match self.fit(label, &numbered) {
+            Some(text) => {
+                // Later includes can only refer back to a copy the model got in full
+                if self.dedup && text == numbered {
+                    self.sent.insert(key, content.to_string());
+                }
+                output.push_str(&self.rendering.file(path, detail, &text));
+            }
+            None => output.push_str(&self.rendering.note(path, &format!("omitted, include budget used up; {} bytes", numbered.len()))),
+        }
+    }
+
+    // With `dedup`, stand in for a file sent earlier in the conversation: a short
+    // reference if it's unchanged, or a diff if that's smaller than the file.
+    // Returns whether it did.
+    fn write_repeat(&mut self, output: &mut String, path: &Path, detail: Option<&str>, label: &str, key: &str, content: &str) -> bool {
+        let Some(earlier) = self.sent.get(key) else {
+            return false;
+        };
+        if earlier == content {
+            self.stats.unchanged.push(label.to_string());
+            output.push_str(&self.rendering.note(path, "unchanged, see the earlier copy above"));
+            return true;
+        }
+        let diff = TextDiff::from_lines(earlier.as_str(), content)
+            .unified_diff()
+            .context_radius(3)
+            .header("earlier", "now")
+            .to_string();
+        if diff.len() >= content.len() {
+            return false;
+        }
+        self.stats.diffed.push(label.to_string());
+        match self.fit(label.to_string(), &diff) {
+            Some(text) => {
+                // The model can only follow the diff if it got all of it
+                if text == diff {
+                    self.sent.insert(key.to_string(), content.to_string());
+                }
+                output.push_str(&self.rendering.changes(path, detail, &text));
+            }
+            None => output.push_str(&self.rendering.note(path, &format!("changes omitted, include budget used up; {} bytes", diff.len()))),
+        }
+        true
+    }
+
+    // Cut text down to what's left of the budget, or `None` if it's used up
+    fn fit(&mut self, label: String, content: &str) -> Option<String> {
+        let remaining = self.budget.per_placeholder.map(|max| max.saturating_sub(self.stats.used));
+        let allowed = match (self.budget.per_file, remaining) {
+            (Some(file), Some(remaining)) => Some(file.min(remaining)),
+            (file, remaining) => file.or(remaining),
+        };
+
+        let text = match allowed {
+            Some(allowed) if content.len() as u64 > allowed => {
+                if remaining.is_some_and(|remaining| remaining < MIN_EXCERPT_BYTES) {
+                    self.stats.omitted.push((label, content.len()));
+                    return None;
+                }
+                let trimmed = trim_to_budget(content, allowed as usize);
+                self.stats.trimmed.push((label, content.len(), allowed as usize));
+                trimmed
+            }
+            _ => content.to_string(),
+        };
+        self.stats.included += 1;
+        self.stats.used += text.len() as u64;
+        Some(text)
+    }
+
+    // Every file included in a prompt is read here, through the snapshot store in snapshot mode
+    fn read_file(&mut self, path: &Path) -> io::Result<FileContent> {
+        let bytes = match &mut self.snapshots {
+            Some(snapshots) => snapshots.read(path)?,
+            None => fs::read(path)?,
+        };
+        Ok(decode(bytes))
+    }
+
+    fn is_frozen(&self, path: &Path) -> bool {
+        self.snapshots.as_ref().is_some_and(|snapshots| snapshots.has(path))
+    }
+
+    // A note for the model on what the budget left out of a placeholder
+    fn budget_note(&self) -> String {
+        let stats = &self.stats;
+        if stats.trimmed.is_empty() && stats.omitted.is_empty() {
+            return String::new();
+        }
+        let mut note = String::from("(Include budget reached:");
+        if !stats.trimmed.is_empty() {
+            let trimmed: Vec<String> = stats
+                .trimmed
+                .iter()
+                .map(|(path, original, kept)| format!("{} ({} of {} bytes)", path, kept, original))
+                .collect();
+            write!(note, " trimmed to a head and tail excerpt: {}.", trimmed.join(", ")).expect("Failed to write to String");
+        }
+        if !stats.omitted.is_empty() {
+            let omitted: Vec<String> = stats.omitted.iter().map(|(path, bytes)| format!("{} ({} bytes)", path, bytes)).collect();
+            write!(note, " omitted: {}.", omitted.join(", ")).expect("Failed to write to String");
+        }
+        note.push_str(")\n\n");
+        note
+    }
+
+    // Report what a placeholder included, if anything was left out or cut down
+    fn print_summary(&self, placeholder: &str) {
+        let stats = &self.stats;
+        if stats.binary == 0
+            && stats.unreadable.is_empty()
+            && stats.trimmed.is_empty()
+            && stats.omitted.is_empty()
+            && stats.unchanged.is_empty()
+            && stats.diffed.is_empty()
+        {
+            return;
+        }
+        let mut summary = format!(
+            "Expanded {}: {} file{} included, {} binary file{} listed without contents, {} unreadable, {} trimmed, {} omitted",
+            placeholder,
+            stats.included,
+            if stats.included == 1 { "" } else { "s" },
+            stats.binary,
+            if stats.binary == 1 { "" } else { "s" },
+            stats.unreadable.len(),
+            stats.trimmed.len(),
+            stats.omitted.len()
+        );
+        if !stats.unchanged.is_empty() || !stats.diffed.is_empty() {
+            write!(summary, ", {} unchanged since sent earlier, {} sent as diffs", stats.unchanged.len(), stats.diffed.len())
+                .expect("Failed to write to String");
+        }
+        println!("{}", summary);
+        for unreadable in &stats.unreadable {
+            println!("  Skipped {}", unreadable);
+        }
+        for (path, original, kept) in &stats.trimmed {
+            println!("  Trimmed {} from {} to {} bytes", path, original, kept);
+        }
+        for (path, bytes) in &stats.omitted {
+            println!("  Omitted {} ({} bytes): budget used up", path, bytes);
+        }
+        for path in &stats.diffed {
+            println!("  Sent {} as a diff against its earlier copy", path);
+        }
+    }
+}
+
+fn decode(bytes: Vec<u8>) -> FileContent {
+    if bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0) {
+        return FileContent::Binary(bytes.len() as u64);
+    }
+    // Text in another encoding is still worth sending, with odd bytes replaced
+    FileContent::Text(match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    })
+}
+
+// Split inline options (`?max=40k&file=8k`) off a placeholder's path, returning the
+// budget they give
+fn split_options(path_str: &str, budget: Budget) -> io::Result<(&str, Budget)> {
+    let re = Regex::new(r"^(.*?)\?(\w+=[^&\s]*(?:&\w+=[^&\s]*)*)$").unwrap();
+    let Some(cap) = re.captures(path_str) else {
+        return Ok((path_str, budget));
+    };
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut budget = budget;
+    for option in cap.get(2).unwrap().as_str().split('&') {
+        let (key, value) = option.split_once('=').unwrap();
+        match key {
+            "max" => budget.per_placeholder = Some(parse_size(value).map_err(invalid)?),
+            "file" => budget.per_file = Some(parse_size(value).map_err(invalid)?),
+            _ => return Err(invalid(format!("Unknown option '{}': expected max= or file=", key))),
+        }
+    }
+    Ok((cap.get(1).unwrap().as_str(), budget))
+}
+
+// Cut text down to about `budget` bytes: whole lines from its head (two thirds)
+// and tail (one third), with a marker saying what was left out between them
+fn trim_to_budget(text: &str, budget: usize) -> String {
+    let head_budget = budget * 2 / 3;
+    let tail_budget = budget - head_budget;
+
+    // End the head at a line break if one fits, else mid-line
+    let head_limit = floor_char_boundary(text, head_budget);
+    let head_end = match text[..head_limit].rfind('\n') {
+        Some(i) => i + 1,
+        None => head_limit,
+    };
+    let tail_from = ceil_char_boundary(text, text.len() - tail_budget);
+    let mut tail_start = text[tail_from..].find('\n').map(|i| tail_from + i + 1).unwrap_or(text.len());
+    // Text without line breaks is cut mid-line at both ends
+    if tail_start == text.len() && head_end == head_limit {
+        tail_start = tail_from;
+    }
+    let tail_start = tail_start.max(head_end);
+
+    let skipped = &text[head_end..tail_start];
+    format!(
+        "{}[... {} lines, {} bytes omitted ...]\n{}",
+        &text[..head_end],
+        skipped.lines().count(),
+        skipped.len(),
+        &text[tail_start..]
+    )
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+fn expand_dir_tree(path_str: &str, budget: Budget, rendering: &Rendering) -> io::Result<String> {
+    let path = Path::new(path_str);
+    if !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+    }
+    if !path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is not a directory"));
+    }
+
+    let mut listing = String::new();
+    let entries: Vec<(PathBuf, bool)> = walk(path).collect();
+    if entries.is_empty() {
+        listing.push_str("(empty directory)\n");
+    } else {
+        for (entry, is_dir) in entries {
+            let rel_path = entry.strip_prefix(path).unwrap();
+            let indent = "  ".repeat(rel_path.components().count() - 1);
+            if is_dir {
+                writeln!(&mut listing, "{}{}/", indent, rel_path.display()).expect("Failed to write to String");
+            } else {
+                writeln!(&mut listing, "{}{}", indent, rel_path.display()).expect("Failed to write to String");
+            }
+        }
+    }
+    // A listing is one include, so the per-placeholder budget caps it
+    if let Some(max) = budget.per_placeholder.filter(|&max| listing.len() as u64 > max) {
+        println!("Trimmed directory listing of {} from {} to {} bytes", path.display(), listing.len(), max);
+        listing = trim_to_budget(&listing, max as usize);
+    }
+    Ok(rendering.directory(path, &listing))
+}
+
+// The entries under a directory that aren't ignored, sorted by path, with whether
+// each is a directory
+fn walk(root: &Path) -> impl Iterator<Item = (PathBuf, bool)> {
+    let mut entries: Vec<(PathBuf, bool)> = WalkBuilder::new(root)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        // Honour .gitignore files outside git repositories too
+        .require_git(false)
+        .build()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.depth() > 0)
+        .map(|e| {
+            let is_dir = e.file_type().is_some_and(|t| t.is_dir());
+            (e.into_path(), is_dir)
+        })
+        .collect();
+    entries.sort();
+    entries.into_iter()
+}
+
+// The files matching a glob, walking from its literal prefix so ignore rules apply
+fn glob_files(pattern_str: &str) -> io::Result<Vec<PathBuf>> {
+    let pattern = Pattern::new(pattern_str).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    let literal: Vec<&str> = pattern_str
+        .split('/')
+        .take_while(|part| !part.contains(['*', '?', '[']))
+        .collect();
+    let base = match literal.join("/") {
+        base if base.is_empty() && pattern_str.starts_with('/') => PathBuf::from("/"),
+        base if base.is_empty() => PathBuf::from("."),
+        base => PathBuf::from(base),
+    };
+
+    Ok(walk(&base)
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(p, _)| p)
+        // A walk from "." yields "./x", which a pattern like "*.rs" is written without
+        .map(|p| if literal.is_empty() { p.strip_prefix(".").map(Path::to_path_buf).unwrap_or(p) } else { p })
+        .filter(|p| pattern.matches_path_with(p, options))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{include, Markers, Message};
+
+    fn expander(dedup: bool) -> Expander {
+        Expander {
+            budget: Budget::default(),
+            rendering: Rendering::default(),
+            shell: ShellPolicy { allow: Vec::new(), timeout: 5, max_output: 10_000 },
+            pages: HashMap::new(),
+            dedup,
+            sent: HashMap::new(),
+            snapshots: None,
+            stats: ExpansionStats::default(),
+        }
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gchat-expand-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("40000"), Ok(40_000));
+        assert_eq!(parse_size("40k"), Ok(40_000));
+        assert_eq!(parse_size("1.5M"), Ok(1_500_000));
+        assert_eq!(parse_size("512b"), Ok(512));
+        assert_eq!(parse_size("10kt"), Ok(40_000));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("-1k").is_err());
+    }
+
+    #[test]
+    fn inline_options() {
+        let budget = Budget { per_file: Some(100), per_placeholder: None };
+        let (path, budget) = split_options("src?max=2k&file=1k", budget).unwrap();
+        assert_eq!((path, budget.per_placeholder, budget.per_file), ("src", Some(2_000), Some(1_000)));
+        assert_eq!(split_options("src/a.rs", budget).unwrap().0, "src/a.rs");
+        assert!(split_options("src?size=2k", budget).is_err());
+    }
+
+    #[test]
+    fn trim_keeps_whole_lines_from_head_and_tail() {
+        let text: String = (1..=100).map(|i| format!("line {:03}\n", i)).collect();
+        let trimmed = trim_to_budget(&text, 90);
+        assert!(trimmed.starts_with("line 001\nline 002\nline 003\nline 004\nline 005\nline 006\n[... "));
+        assert!(trimmed.ends_with("]\nline 098\nline 099\nline 100\n"));
+        assert!(trimmed.contains("[... 91 lines, 819 bytes omitted ...]"));
+    }
+
+    #[test]
+    fn trim_cuts_long_lines_at_char_boundaries() {
+        let text = "é".repeat(100);
+        let trimmed = trim_to_budget(&text, 31);
+        assert!(trimmed.starts_with(&"é".repeat(10)));
+        assert!(trimmed.ends_with(&"é".repeat(5)));
+        assert!(trimmed.contains("[... 1 lines, 170 bytes omitted ...]"));
+    }
+
+    // Include a file's content as its own placeholder, as a prompt would
+    fn include(expander: &mut Expander, path: &str, content: &str) -> String {
+        expander.stats = ExpansionStats::default();
+        let mut output = String::new();
+        expander.write_text(&mut output, Path::new(path), None, content, 1);
+        output
+    }
+
+    fn lines(count: usize, changed: Option<usize>) -> String {
+        (1..=count).map(|i| if Some(i) == changed { format!("changed {}\n", i) } else { format!("line {}\n", i) }).collect()
+    }
+
+    #[test]
+    fn repeats_are_sent_as_references_or_diffs() {
+        let mut expander = expander(true);
+        let original = lines(40, None);
+        assert!(include(&mut expander, "src/a.rs", &original).starts_with("Contents of src/a.rs:\n```rust\nline 1\n"));
+
+        // Unchanged, even written another way: a reference
+        let repeat = include(&mut expander, "./src/a.rs", &original);
+        assert_eq!(repeat, "Contents of ./src/a.rs: (unchanged, see the earlier copy above)\n\n");
+
+        // A small change: a diff, against which the next change is taken
+        let diff = include(&mut expander, "src/a.rs", &lines(40, Some(20)));
+        assert!(diff.starts_with("Changes to src/a.rs since the earlier copy above:\n```diff\n"), "{}", diff);
+        assert!(diff.contains("-line 20\n+changed 20\n"));
+        let diff = include(&mut expander, "src/a.rs", &lines(40, Some(30)));
+        assert!(diff.contains("-changed 20\n+line 20\n") && diff.contains("-line 30\n+changed 30\n"), "{}", diff);
+
+        // Mostly rewritten: the diff would be bigger, so the whole file again
+        let rewritten: String = (1..=40).map(|i| format!("new {}\n", i)).collect();
+        assert!(include(&mut expander, "src/a.rs", &rewritten).starts_with("Contents of src/a.rs:\n"));
+        assert_eq!(expander.sent["src/a.rs"], rewritten);
+
+        // Without dedup every copy is sent in full
+        let mut expander = self::expander(false);
+        include(&mut expander, "src/a.rs", &original);
+        assert!(include(&mut expander, "src/a.rs", &original).starts_with("Contents of src/a.rs:\n"));
+    }
+
+    #[test]
+    fn only_a_copy_sent_in_full_counts() {
+        let mut expander = expander(true);
+        let original = lines(400, None);
+
+        // Trimmed by the budget: a later copy is sent in full rather than pointing back
+        expander.budget.per_file = Some(1_000);
+        let trimmed = include(&mut expander, "src/a.rs", &original);
+        assert!(trimmed.contains("lines, ") && trimmed.contains(" omitted ...]"), "{}", trimmed);
+        assert!(!expander.sent.contains_key("src/a.rs"));
+        expander.budget.per_file = None;
+        assert!(include(&mut expander, "src/a.rs", &original).starts_with("Contents of src/a.rs:\n"));
+        assert_eq!(expander.sent["src/a.rs"], original);
+
+        // A trimmed diff leaves the earlier copy as the one to diff against
+        let changed: String = (1..=400).map(|i| if i % 40 == 0 { format!("changed {}\n", i) } else { format!("line {}\n", i) }).collect();
+        expander.budget.per_file = Some(300);
+        let diff = include(&mut expander, "src/a.rs", &changed);
+        assert!(diff.starts_with("Changes to src/a.rs") && diff.contains(" omitted ...]"), "{}", diff);
+        assert_eq!(expander.sent["src/a.rs"], original);
+        expander.budget.per_file = None;
+        let diff = include(&mut expander, "src/a.rs", &changed);
+        assert!(diff.starts_with("Changes to src/a.rs") && !diff.contains(" omitted ...]"), "{}", diff);
+        assert_eq!(expander.sent["src/a.rs"], changed);
+        assert!(include(&mut expander, "src/a.rs", &changed).contains("(unchanged, see the earlier copy above)"));
+    }
+
+    #[test]
+    fn includes_in_expanded_content_are_left_alone() {
+        let dir = scratch("includes");
+        fs::write(dir.join("chat.md"), "").unwrap();
+        fs::write(dir.join("other.md"), "USER PROMPT:\nSecret question\n").unwrap();
+        fs::write(dir.join("notes.md"), "See @c:other.md for the background\n").unwrap();
+
+        let markers = Markers::from_style("plain").unwrap();
+        let prompt = Message { role: "user".to_string(), content: format!("@f:{}", dir.join("notes.md").display()) };
+        let (messages, held) = include::include_conversations(vec![prompt], &dir.join("chat.md"), &dir, &markers).unwrap();
+        let mut expander = expander(false);
+        let messages: Vec<Message> = messages
+            .into_iter()
+            .map(|m| Message { content: expander.expand_placeholders(&m.content, true).unwrap(), ..m })
+            .collect();
+        let content = &held.restore(messages)[0].content;
+        assert!(content.contains("See @c:other.md for the background"));
+        assert!(!content.contains("Secret question"));
+    }
+}
@@ -0,0 +1,324 @@
+// Exporting chat files as JSON, JSONL or standalone HTML, and importing
+// OpenAI-style message arrays back into a chat file.
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use serde_json::Value;
+use syntect::highlighting::ThemeSet;
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::SyntaxSet;
+
+use crate::{escape_markers, parse_chat_messages, parse_front_matter, FrontMatter, Markers, Message};
+
+// Render a chat file in the given format ("json", "jsonl" or "html")
+pub fn export_chat(chat_path: &Path, format: &str, markers: &Markers) -> io::Result<String> {
+    let content = fs::read_to_string(chat_path)?;
+    let mut messages = parse_chat_messages(&content, markers);
+
+    // The chat's system prompt travels with the conversation
+    if let Some(system) = parse_front_matter(&content, Some(markers))?.and_then(|fm| fm.system) {
+        messages.insert(0, Message {
+            role: "system".to_string(),
+            content: system.trim().to_string(),
+        });
+    }
+
+    match format {
+        "json" => serde_json::to_string_pretty(&messages)
+            .map(|json| json + "\n")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        "jsonl" => {
+            let mut output = String::new();
+            for msg in &messages {
+                let line = serde_json::to_string(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                writeln!(output, "{}", line).expect("Failed to write to String");
+            }
+            Ok(output)
+        }
+        "html" => Ok(render_html(&title_of(chat_path), &messages)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown export format '{}': expected json, jsonl or html", format),
+        )),
+    }
+}
+
+// Write a chat file from an OpenAI-style conversation: a JSON array of messages,
+// an object with a "messages" array, or JSONL with one message per line.
+// System messages become the front matter's system prompt.
+pub fn import_chat(source: &Path, to: Option<PathBuf>, markers: &Markers) -> io::Result<PathBuf> {
+    let raw = fs::read_to_string(source)?;
+    let messages = read_messages(&raw)?;
+
+    let chat_path = to.unwrap_or_else(|| source.with_extension("md"));
+    if chat_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", chat_path.display()),
+        ));
+    }
+
+    let mut system = Vec::new();
+    let mut body = String::new();
+    for msg in &messages {
+        let marker = match msg.role.as_str() {
+            "system" | "developer" => {
+                system.push(msg.content.trim().to_string());
+                continue;
+            }
+            "user" => &markers.user,
+            "assistant" => &markers.grok,
+            other => {
+                println!("Warning: Skipping message with unsupported role '{}'", other);
+                continue;
+            }
+        };
+        write!(body, "{}\n{}\n\n", marker, escape_markers(msg.content.trim(), markers)).expect("Failed to write to String");
+    }
+    // Leave an empty prompt to continue from, unless the conversation ends on one
+    if messages.iter().rev().find(|m| m.role == "user" || m.role == "assistant").map(|m| m.role.as_str()) != Some("user") {
+        writeln!(body, "{}\n", markers.user).expect("Failed to write to String");
+    }
+
+    let mut output = String::new();
+    if !system.is_empty() {
+        let front_matter = FrontMatter {
+            system: Some(system.join("\n\n")),
+            ..FrontMatter::default()
+        };
+        let header = serde_norway::to_string(&front_matter).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write!(output, "---\n{}---\n", header).expect("Failed to write to String");
+    }
+    output.push_str(&body);
+
+    fs::write(&chat_path, output)?;
+    Ok(chat_path)
+}
+
+fn read_messages(raw: &str) -> io::Result<Vec<Message>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let values = match serde_json::from_str::<Value>(raw) {
+        Ok(Value::Array(values)) => values,
+        Ok(Value::Object(mut object)) => match object.remove("messages") {
+            Some(Value::Array(values)) => values,
+            _ => return Err(invalid("Expected a \"messages\" array".to_string())),
+        },
+        Ok(_) => return Err(invalid("Expected an array of messages".to_string())),
+        // Not a single JSON document, so try one message per line
+        Err(_) => raw
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| invalid(e.to_string())))
+            .collect::<io::Result<_>>()?,
+    };
+
+    values
+        .iter()
+        .map(|value| {
+            let role = value.get("role").and_then(Value::as_str).ok_or_else(|| invalid("Message without a role".to_string()))?;
+            Ok(Message {
+                role: role.to_string(),
+                content: content_text(value.get("content").unwrap_or(&Value::Null)),
+            })
+        })
+        .collect()
+}
+
+// Message content is either a string or an array of typed parts, of which only
+// the text parts are kept
+fn content_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        _ => String::new(),
+    }
+}
+
+fn title_of(chat_path: &Path) -> String {
+    chat_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "gchat".to_string())
+}
+
+// A standalone HTML page with each turn rendered from Markdown, and code blocks
+// syntax-highlighted with inline styles
+fn render_html(title: &str, messages: &[Message]) -> String {
+    let syntaxes = SyntaxSet::load_defaults_newlines();
+    let themes = ThemeSet::load_defaults();
+    let theme = &themes.themes["InspiredGitHub"];
+
+    let mut output = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+<style>
+body {{ font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; max-width: 860px; margin: 2em auto; padding: 0 1em; line-height: 1.5; color: #1f2328; }}
+section {{ border: 1px solid #d0d7de; border-radius: 6px; padding: 0 1em; margin: 1em 0; }}
+section.user {{ background: #f6f8fa; }}
+section.system {{ background: #fff8c5; }}
+h2.role {{ font-size: 0.9em; text-transform: uppercase; color: #656d76; }}
+pre {{ padding: 0.8em; overflow-x: auto; border-radius: 6px; }}
+code {{ font-family: ui-monospace, SFMono-Regular, Menlo, monospace; font-size: 0.9em; }}
+</style>
+</head>
+<body>
+<h1>{}</h1>
+"#,
+        escape_html(title),
+        escape_html(title)
+    );
+
+    for msg in messages {
+        let label = match msg.role.as_str() {
+            "user" => "User",
+            "assistant" => "Grok",
+            "system" => "System",
+            other => other,
+        };
+        write!(
+            output,
+            "<section class=\"{}\">\n<h2 class=\"role\">{}</h2>\n{}</section>\n",
+            escape_html(&msg.role),
+            escape_html(label),
+            render_markdown(&msg.content, &syntaxes, theme)
+        )
+        .expect("Failed to write to String");
+    }
+
+    output.push_str("</body>\n</html>\n");
+    output
+}
+
+fn render_markdown(text: &str, syntaxes: &SyntaxSet, theme: &syntect::highlighting::Theme) -> String {
+    let mut events = Vec::new();
+    let mut code: Option<(String, String)> = None;
+
+    for event in Parser::new_ext(text, Options::all()) {
+        match (event, code.as_mut()) {
+            (Event::Start(Tag::CodeBlock(kind)), _) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            (Event::Text(text), Some((_, source))) => source.push_str(&text),
+            (Event::End(TagEnd::CodeBlock), Some((lang, source))) => {
+                let syntax = syntaxes
+                    .find_syntax_by_token(lang)
+                    .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+                let highlighted = highlighted_html_for_string(source, syntaxes, syntax, theme)
+                    .unwrap_or_else(|_| format!("<pre><code>{}</code></pre>\n", escape_html(source)));
+                events.push(Event::Html(highlighted.into()));
+                code = None;
+            }
+            // Raw HTML in a conversation is shown, not interpreted
+            (Event::Html(raw), _) | (Event::InlineHtml(raw), _) => events.push(Event::Text(raw)),
+            // and links can't run script in the page
+            (Event::Start(Tag::Link { link_type, dest_url, title, id }), _) => events.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url: if is_safe_url(&dest_url) { dest_url } else { "#".into() },
+                title,
+                id,
+            })),
+            (Event::Start(Tag::Image { link_type, dest_url, title, id }), _) => events.push(Event::Start(Tag::Image {
+                link_type,
+                dest_url: if is_safe_url(&dest_url) { dest_url } else { "#".into() },
+                title,
+                id,
+            })),
+            (event, _) => events.push(event),
+        }
+    }
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events.into_iter());
+    rendered
+}
+
+// Web and mail links, and relative ones, are kept; any other scheme (`javascript:`,
+// `data:`, ...) is dropped
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim();
+    match url.find(':') {
+        // A colon after a '/', '?' or '#' is part of a relative URL, not a scheme
+        Some(colon) if !url[..colon].contains(['/', '?', '#']) => {
+            matches!(url[..colon].to_ascii_lowercase().as_str(), "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gchat-export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn markdown(text: &str) -> String {
+        let themes = ThemeSet::load_defaults();
+        render_markdown(text, &SyntaxSet::load_defaults_newlines(), &themes.themes["InspiredGitHub"])
+    }
+
+    #[test]
+    fn links_only_go_to_safe_places() {
+        for url in ["https://example.com/a", "http://x", "mailto:a@example.com", "docs/a.md", "#top", "/a?b=c:d", "a/b:c"] {
+            assert!(is_safe_url(url), "{}", url);
+        }
+        for url in ["javascript:alert(1)", " JavaScript:alert(1)", "data:text/html,x", "vbscript:x", "java\tscript:x"] {
+            assert!(!is_safe_url(url), "{}", url);
+        }
+
+        let html = markdown("[x](javascript:alert(1)) ![y](data:image/svg+xml,z) <javascript:alert(2)> [ok](https://example.com)");
+        assert!(!html.contains("href=\"javascript"), "{}", html);
+        assert!(!html.contains("src=\"data:"), "{}", html);
+        assert!(html.contains("href=\"https://example.com\""), "{}", html);
+    }
+
+    #[test]
+    fn json_survives_import_and_export() {
+        let dir = scratch("round-trip");
+        let markers = Markers::from_style("plain").unwrap();
+        let messages = vec![
+            Message { role: "system".to_string(), content: "Be brief.\nAnswer in English.".to_string() },
+            Message { role: "user".to_string(), content: "What does a chat file look like?".to_string() },
+            Message { role: "assistant".to_string(), content: "Like this:\n\nUSER PROMPT:\nHi\n\\GROK RESPONSE:".to_string() },
+            Message { role: "user".to_string(), content: "Thanks".to_string() },
+        ];
+        let source = dir.join("chat.json");
+        fs::write(&source, serde_json::to_string(&messages).unwrap()).unwrap();
+
+        let chat = import_chat(&source, None, &markers).unwrap();
+        let content = fs::read_to_string(&chat).unwrap();
+        assert!(content.contains("\\USER PROMPT:\nHi\n\\\\GROK RESPONSE:\n"), "{}", content);
+        let exported: Vec<Message> = serde_json::from_str(&export_chat(&chat, "json", &markers).unwrap()).unwrap();
+        let pairs = |messages: &[Message]| -> Vec<(String, String)> {
+            messages.iter().map(|m| (m.role.clone(), m.content.clone())).collect()
+        };
+        assert_eq!(pairs(&exported), pairs(&messages));
+    }
+}
@@ -0,0 +1,179 @@
+// Conversation branching: `gchat fork` copies the first turns of a chat file into a
+// new file whose front matter records where it came from, and `gchat tree` shows
+// every fork that shares an original conversation.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{display_path, escape_markers, parse_chat_messages, parse_front_matter, FrontMatter, Markers};
+
+// Copy the first `at` turns of a chat file into a new chat file, returning its path
+pub fn fork_chat(path: &Path, at: usize, to: Option<PathBuf>, markers: &Markers) -> io::Result<PathBuf> {
+    let content = fs::read_to_string(path)?;
+    let messages = parse_chat_messages(&content, markers);
+    if at == 0 || at > messages.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot fork at turn {}: {} has {} turns", at, path.display(), messages.len()),
+        ));
+    }
+
+    let fork_path = to.unwrap_or_else(|| next_fork_path(path));
+    if fork_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", fork_path.display()),
+        ));
+    }
+
+    // Keep the original's per-chat settings, and record the branch point
+    let mut front_matter = parse_front_matter(&content, Some(markers))?.unwrap_or_default();
+    front_matter.parent = Some(parent_reference(path, &fork_path)?);
+    front_matter.forked_at = Some(at);
+    let header = serde_norway::to_string(&front_matter).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut output = format!("---\n{}---\n", header);
+    for msg in &messages[..at] {
+        let marker = if msg.role == "user" { &markers.user } else { &markers.grok };
+        write!(output, "{}\n{}\n\n", marker, escape_markers(&msg.content, markers)).expect("Failed to write to String");
+    }
+    // Leave an empty prompt to continue from, unless the fork ends on a pending prompt
+    if messages[at - 1].role != "user" {
+        writeln!(output, "{}\n", markers.user).expect("Failed to write to String");
+    }
+
+    fs::write(&fork_path, output)?;
+    Ok(fork_path)
+}
+
+// Render the family of forks a chat file belongs to, starting from the original
+pub fn fork_tree(path: &Path, markers: &Markers) -> io::Result<String> {
+    let path = path.canonicalize()?;
+
+    // Walk up to the original conversation, noting every directory the family touches
+    let mut dirs = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut root = path.clone();
+    dirs.insert(dir_of(&root));
+    while visited.insert(root.clone()) {
+        let parent = match read_front_matter(&root, markers).and_then(|fm| fm.parent) {
+            Some(parent) => dir_of(&root).join(parent),
+            None => break,
+        };
+        match parent.canonicalize() {
+            Ok(parent) => {
+                dirs.insert(dir_of(&parent));
+                root = parent;
+            }
+            Err(_) => {
+                println!("Warning: Parent {} of {} not found", parent.display(), root.display());
+                break;
+            }
+        }
+    }
+
+    // Find every chat file in those directories that names a parent
+    let mut children: BTreeMap<PathBuf, Vec<(PathBuf, Option<usize>)>> = BTreeMap::new();
+    for dir in dirs {
+        for entry in fs::read_dir(&dir)?.filter_map(|e| e.ok()) {
+            let file = entry.path();
+            if file.extension().and_then(|e| e.to_str()) != Some("md") {
+                continue;
+            }
+            let Some(fm) = read_front_matter(&file, markers) else {
+                continue;
+            };
+            if let Some(parent) = fm.parent {
+                if let Ok(parent) = dir_of(&file).join(parent).canonicalize() {
+                    children.entry(parent).or_default().push((file.canonicalize()?, fm.forked_at));
+                }
+            }
+        }
+    }
+    for forks in children.values_mut() {
+        forks.sort();
+        forks.dedup();
+    }
+
+    let mut output = String::new();
+    writeln!(output, "{}", describe(&root, None, &path, markers)).expect("Failed to write to String");
+    render_children(&root, "", &children, &path, markers, &mut HashSet::from([root.clone()]), &mut output);
+    Ok(output)
+}
+
+fn render_children(
+    node: &Path,
+    prefix: &str,
+    children: &BTreeMap<PathBuf, Vec<(PathBuf, Option<usize>)>>,
+    current: &Path,
+    markers: &Markers,
+    seen: &mut HashSet<PathBuf>,
+    output: &mut String,
+) {
+    let Some(forks) = children.get(node) else {
+        return;
+    };
+    for (i, (fork, forked_at)) in forks.iter().enumerate() {
+        // Guard against parent links that form a cycle
+        if !seen.insert(fork.clone()) {
+            continue;
+        }
+        let last = i == forks.len() - 1;
+        let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        writeln!(output, "{}{}{}", prefix, branch, describe(fork, *forked_at, current, markers))
+            .expect("Failed to write to String");
+        render_children(fork, &format!("{}{}", prefix, indent), children, current, markers, seen, output);
+    }
+}
+
+// One line of the tree: the file, where it branched off, and how long it is now
+fn describe(path: &Path, forked_at: Option<usize>, current: &Path, markers: &Markers) -> String {
+    let turns = fs::read_to_string(path)
+        .map(|content| parse_chat_messages(&content, markers).len())
+        .unwrap_or(0);
+    let mut line = display_path(path);
+    match forked_at {
+        Some(at) => write!(line, " (forked at turn {}, {} turns)", at, turns),
+        None => write!(line, " ({} turns)", turns),
+    }
+    .expect("Failed to write to String");
+    if path == current {
+        line.push_str(" <- this chat");
+    }
+    line
+}
+
+fn read_front_matter(path: &Path, markers: &Markers) -> Option<FrontMatter> {
+    let content = fs::read_to_string(path).ok()?;
+    parse_front_matter(&content, Some(markers)).ok().flatten()
+}
+
+fn dir_of(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// How a fork refers to its parent: relative to the fork's directory when the
+// parent lives under it, otherwise by absolute path
+fn parent_reference(parent: &Path, fork: &Path) -> io::Result<String> {
+    let parent = parent.canonicalize()?;
+    let fork_dir = dir_of(fork).canonicalize()?;
+    Ok(match parent.strip_prefix(&fork_dir) {
+        Ok(relative) => relative.display().to_string(),
+        Err(_) => parent.display().to_string(),
+    })
+}
+
+// `chat.md` forks to `chat.fork1.md`, then `chat.fork2.md`, and so on
+fn next_fork_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("chat");
+    (1..)
+        .map(|n| dir_of(path).join(format!("{}.fork{}.md", stem, n)))
+        .find(|candidate| !candidate.exists())
+        .expect("Ran out of fork names")
+}
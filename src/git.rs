@@ -0,0 +1,181 @@
+// Placeholders that read the local git repository through the git command line:
+// `@git:diff` (unstaged changes), `@git:diff:staged`, `@git:log:N`,
+// `@git:show:<rev>`, and `@f:<rev>:path` for a file as of a revision. Nothing
+// here talks to a remote.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+const DEFAULT_LOG_COMMITS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitInclude {
+    Diff { staged: bool },
+    Log(usize),
+    Show(String),
+}
+
+impl fmt::Display for GitInclude {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitInclude::Diff { staged: false } => write!(f, "git diff"),
+            GitInclude::Diff { staged: true } => write!(f, "git diff --staged"),
+            GitInclude::Log(count) => write!(f, "git log -n {}", count),
+            GitInclude::Show(rev) => write!(f, "git show {}", rev),
+        }
+    }
+}
+
+impl GitInclude {
+    // Parse what follows `@git:`, e.g. "diff", "diff:staged", "log:5" or "show:HEAD~2"
+    pub fn parse(spec: &str) -> io::Result<GitInclude> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let (command, arg) = match spec.split_once(':') {
+            Some((command, arg)) => (command, Some(arg)),
+            None => (spec, None),
+        };
+        match (command, arg) {
+            ("diff", None) => Ok(GitInclude::Diff { staged: false }),
+            ("diff", Some("staged" | "cached")) => Ok(GitInclude::Diff { staged: true }),
+            ("log", None) => Ok(GitInclude::Log(DEFAULT_LOG_COMMITS)),
+            ("log", Some(count)) => match count.parse() {
+                Ok(count) if count > 0 => Ok(GitInclude::Log(count)),
+                _ => Err(invalid(format!("Invalid commit count '{}': expected @git:log:N", count))),
+            },
+            ("show", Some(rev)) => Ok(GitInclude::Show(check_revision(rev)?.to_string())),
+            _ => Err(invalid(format!(
+                "Unknown git placeholder '{}': expected diff, diff:staged, log:N or show:<rev>",
+                spec
+            ))),
+        }
+    }
+
+    // The fence info string for this output
+    pub fn language(&self) -> &'static str {
+        match self {
+            GitInclude::Log(_) => "",
+            _ => "diff",
+        }
+    }
+
+    pub fn run(&self) -> io::Result<String> {
+        let output = match self {
+            GitInclude::Diff { staged } => {
+                let mut args = vec!["diff", "--no-color", "--no-ext-diff"];
+                if *staged {
+                    args.push("--staged");
+                }
+                git(&args)?
+            }
+            GitInclude::Log(count) => git(&["log", "--no-color", "--stat", "--date=iso", "-n", &count.to_string()])?,
+            GitInclude::Show(rev) => git(&["show", "--no-color", "--no-ext-diff", "--stat", "--patch", rev, "--"])?,
+        };
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+}
+
+// Split `<rev>:path` into its revision and path, unless it names an existing
+// file or is a `path::symbol` include
+pub fn split_revision(path: &str) -> Option<(&str, &str)> {
+    if Path::new(path).exists() {
+        return None;
+    }
+    let (rev, file) = path.split_once(':')?;
+    if rev.is_empty() || file.is_empty() || file.starts_with(':') || rev.starts_with('-') {
+        return None;
+    }
+    Some((rev, file))
+}
+
+// A file's contents as of a revision, with `path` relative to the current directory
+pub fn file_at(rev: &str, path: &str) -> io::Result<Vec<u8>> {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    git(&["cat-file", "blob", &format!("{}:./{}", check_revision(rev)?, path)])
+}
+
+// Revisions are passed to git as arguments, so one mustn't look like an option
+fn check_revision(rev: &str) -> io::Result<&str> {
+    if rev.is_empty() || rev.starts_with('-') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid revision '{}'", rev)));
+    }
+    Ok(rev)
+}
+
+fn git(args: &[&str]) -> io::Result<Vec<u8>> {
+    let output = Command::new("git")
+        .args(args)
+        .env("GIT_PAGER", "cat")
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run git: {}", e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("git {} failed: {}", args[0], stderr.trim())));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        for (spec, include, command) in [
+            ("diff", GitInclude::Diff { staged: false }, "git diff"),
+            ("diff:staged", GitInclude::Diff { staged: true }, "git diff --staged"),
+            ("diff:cached", GitInclude::Diff { staged: true }, "git diff --staged"),
+            ("log", GitInclude::Log(DEFAULT_LOG_COMMITS), "git log -n 10"),
+            ("log:3", GitInclude::Log(3), "git log -n 3"),
+            ("show:HEAD~2", GitInclude::Show("HEAD~2".to_string()), "git show HEAD~2"),
+            ("show:v1.0:src", GitInclude::Show("v1.0:src".to_string()), "git show v1.0:src"),
+        ] {
+            let parsed = GitInclude::parse(spec).unwrap();
+            assert_eq!(parsed, include, "{}", spec);
+            assert_eq!(parsed.to_string(), command, "{}", spec);
+        }
+        assert_eq!(GitInclude::Log(3).language(), "");
+        assert_eq!(GitInclude::Diff { staged: false }.language(), "diff");
+    }
+
+    #[test]
+    fn invalid_placeholders() {
+        for spec in ["", "status", "diff:all", "log:0", "log:x", "log:-1", "show", "show:", "show:--output=x", "push:origin"] {
+            assert!(GitInclude::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn revisions() {
+        for (path, split) in [
+            ("HEAD~2:src/main.rs", Some(("HEAD~2", "src/main.rs"))),
+            ("v1.0:./Cargo.toml", Some(("v1.0", "./Cargo.toml"))),
+            ("main:a:b.txt", Some(("main", "a:b.txt"))),
+            ("src/main.rs", None),
+            // `path::symbol` is an excerpt, not a revision
+            ("src/main.rs::Settings", None),
+            (":src/main.rs", None),
+            ("HEAD:", None),
+            ("--output=x:a.rs", None),
+        ] {
+            assert_eq!(split_revision(path), split, "{}", path);
+        }
+
+        // A file that exists is read from disk, colon or not
+        let dir = std::env::temp_dir().join(format!("gchat-git-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes:today.md");
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(split_revision(path.to_str().unwrap()), None);
+    }
+
+    #[test]
+    fn revisions_mustnt_look_like_options() {
+        assert_eq!(check_revision("HEAD~1").unwrap(), "HEAD~1");
+        assert_eq!(check_revision("a1b2c3").unwrap(), "a1b2c3");
+        for rev in ["", "-p", "--output=/tmp/x"] {
+            assert!(check_revision(rev).is_err(), "{}", rev);
+        }
+    }
+}
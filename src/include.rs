@@ -0,0 +1,289 @@
+// Including other conversations: `@c:path/to/other.md` in a prompt inlines that
+// chat as a delimited transcript, `#3-5` (or `#3`, `#3-`, `#tail=4`) picks which of
+// its turns, and `?as=messages` sends them as real prior messages instead. Only the
+// `@c:` directives written in a prompt count: included text is held back behind a
+// sentinel while placeholders are expanded, so neither it nor a file, page or command
+// output a placeholder expands to can include anything.
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::{display_path, parse_chat_messages, Markers, Message};
+
+// Marks where included text goes, around its index in `Held`
+const SENTINEL: char = '\u{E000}';
+
+// Included conversations, waiting to replace their sentinels once placeholders are expanded
+pub struct Held(Vec<String>);
+
+impl Held {
+    // Put the included text back in place of its sentinels
+    pub fn restore(&self, messages: Vec<Message>) -> Vec<Message> {
+        let re = Regex::new(&format!("{0}(\\d+){0}", SENTINEL)).unwrap();
+        messages
+            .into_iter()
+            .map(|mut msg| {
+                if msg.content.contains(SENTINEL) {
+                    msg.content = re
+                        .replace_all(&msg.content, |cap: &regex::Captures| {
+                            cap[1].parse::<usize>().ok().and_then(|i| self.0.get(i)).cloned().unwrap_or_default()
+                        })
+                        .to_string();
+                }
+                msg
+            })
+            .collect()
+    }
+}
+
+// Resolve the `@c:` includes in the user messages, against the directory of the chat
+// file they appear in. Included files must be inside the project (`root`). The text
+// they add is held back, to be put in place with `Held::restore`.
+pub fn include_conversations(
+    messages: Vec<Message>,
+    chat_path: &Path,
+    root: &Path,
+    markers: &Markers,
+) -> io::Result<(Vec<Message>, Held)> {
+    let mut includes = Includes {
+        root: root.canonicalize()?,
+        stack: vec![chat_path.canonicalize()?],
+        markers,
+    };
+    let mut held = Vec::new();
+    let messages = includes.expand(messages, Some(&mut held))?;
+    Ok((messages, Held(held)))
+}
+
+struct Includes<'a> {
+    root: PathBuf,
+    // The chat files being expanded, innermost last, to catch cycles
+    stack: Vec<PathBuf>,
+    markers: &'a Markers,
+}
+
+impl Includes<'_> {
+    // With `held`, included text is replaced by a sentinel and kept there; the
+    // conversations an included one includes in turn go in directly
+    fn expand(&mut self, messages: Vec<Message>, mut held: Option<&mut Vec<String>>) -> io::Result<Vec<Message>> {
+        let re = Regex::new(r"@c\s*:([^\s#?]+)(?:#([\w=-]+))?(?:\?as=(\w+))?").unwrap();
+        let base = self.stack.last().and_then(|p| p.parent()).map(Path::to_path_buf).unwrap_or_default();
+        let mut hold = |text: String| match held.as_deref_mut() {
+            Some(held) => {
+                held.push(text);
+                format!("{0}{1}{0}", SENTINEL, held.len() - 1)
+            }
+            None => text,
+        };
+
+        let mut expanded = Vec::new();
+        for mut msg in messages {
+            if msg.role != "user" || !re.is_match(&msg.content) {
+                expanded.push(msg);
+                continue;
+            }
+
+            let mut prior = Vec::new();
+            let mut result = String::new();
+            let mut last_end = 0;
+            for cap in re.captures_iter(&msg.content) {
+                let whole = cap.get(0).unwrap();
+                result.push_str(&msg.content[last_end..whole.start()]);
+                last_end = whole.end();
+
+                let as_messages = match cap.get(3).map(|m| m.as_str()) {
+                    None | Some("transcript") => false,
+                    Some("messages") => true,
+                    Some(other) => {
+                        println!("Warning: Unknown include mode '{}' in '{}', expected 'messages' or 'transcript'", other, whole.as_str());
+                        false
+                    }
+                };
+                match self.load(&base, &cap[1], cap.get(2).map(|m| m.as_str())) {
+                    Ok((included, label)) if as_messages => {
+                        prior.extend(included.into_iter().map(|m| Message {
+                            content: hold(m.content),
+                            ..m
+                        }));
+                        println!("Including {} as prior messages", label);
+                    }
+                    Ok((included, label)) => result.push_str(&hold(transcript(&included, &label))),
+                    Err(e) => {
+                        println!("Warning: Failed to include conversation '{}' : {}", whole.as_str(), e);
+                        result.push_str(whole.as_str());
+                    }
+                }
+            }
+            result.push_str(&msg.content[last_end..]);
+            msg.content = result;
+
+            expanded.extend(prior);
+            expanded.push(msg);
+        }
+        Ok(expanded)
+    }
+
+    // Read the selected turns of another chat file, with its own includes expanded,
+    // and a label describing them
+    fn load(&mut self, base: &Path, path: &str, turns: Option<&str>) -> io::Result<(Vec<Message>, String)> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if Path::new(path).is_absolute() {
+            return Err(invalid(format!("{}: absolute paths can't be included", path)));
+        }
+        let path = base.join(path);
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if !canonical.starts_with(&self.root) {
+            return Err(invalid(format!("{}: outside the project directory", path.display())));
+        }
+        if self.stack.contains(&canonical) {
+            let cycle: Vec<String> = self.stack.iter().chain([&canonical]).map(|p| display_path(p)).collect();
+            return Err(invalid(format!("include cycle {}", cycle.join(" -> "))));
+        }
+
+        let content = fs::read_to_string(&canonical)?;
+        let mut messages = parse_chat_messages(&content, self.markers);
+        let total = messages.len();
+        let (start, end) = parse_turns(turns, total)?;
+        messages.truncate(end);
+        messages.drain(..start - 1);
+
+        let label = if (start, end) == (1, total) {
+            format!("{} ({} turns)", display_path(&canonical), total)
+        } else if start == end {
+            format!("{} (turn {} of {})", display_path(&canonical), start, total)
+        } else {
+            format!("{} (turns {}-{} of {})", display_path(&canonical), start, end, total)
+        };
+
+        self.stack.push(canonical);
+        let messages = self.expand(messages, None);
+        self.stack.pop();
+        Ok((messages?, label))
+    }
+}
+
+// A 1-based inclusive range of turns from `N`, `N-M`, `N-` or `tail=K`
+fn parse_turns(spec: Option<&str>, total: usize) -> io::Result<(usize, usize)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if total == 0 {
+        return Err(invalid("the conversation has no turns".to_string()));
+    }
+    let Some(spec) = spec else {
+        return Ok((1, total));
+    };
+
+    let number = |s: &str| {
+        s.parse::<usize>()
+            .map_err(|_| invalid(format!("invalid turn range '{}': expected N, N-M, N- or tail=K", spec)))
+    };
+    let (start, end) = if let Some(count) = spec.strip_prefix("tail=") {
+        (total.saturating_sub(number(count)?) + 1, total)
+    } else if let Some((start, end)) = spec.split_once('-') {
+        (number(start)?, if end.is_empty() { total } else { number(end)? })
+    } else {
+        let turn = number(spec)?;
+        (turn, turn)
+    };
+
+    if start == 0 || start > end || end > total {
+        return Err(invalid(format!("turn range '{}' is out of range: the conversation has {} turns", spec, total)));
+    }
+    Ok((start, end))
+}
+
+fn transcript(messages: &[Message], label: &str) -> String {
+    let mut output = format!("Earlier conversation from {}:\n===== BEGIN CONVERSATION =====\n", label);
+    for msg in messages {
+        let speaker = if msg.role == "user" { "User" } else { "Grok" };
+        write!(output, "{}:\n{}\n\n", speaker, msg.content.trim()).expect("Failed to write to String");
+    }
+    output.push_str("===== END CONVERSATION =====\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Markers;
+
+    // A project directory holding these chat files
+    fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gchat-include-{}-{}", name, std::process::id()));
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn prompt(content: &str) -> Message {
+        Message { role: "user".to_string(), content: content.to_string() }
+    }
+
+    fn include(dir: &Path, root: &Path, content: &str) -> (Vec<Message>, Held) {
+        let markers = Markers::from_style("plain").unwrap();
+        include_conversations(vec![prompt(content)], &dir.join("chat.md"), root, &markers).unwrap()
+    }
+
+    const OTHER: &str = "USER PROMPT:\nWhich parser?\nGROK RESPONSE:\nA hand-written one.\nUSER PROMPT:\nWhy?\n";
+
+    #[test]
+    fn turn_ranges() {
+        assert_eq!(parse_turns(None, 6).unwrap(), (1, 6));
+        assert_eq!(parse_turns(Some("3"), 6).unwrap(), (3, 3));
+        assert_eq!(parse_turns(Some("2-4"), 6).unwrap(), (2, 4));
+        assert_eq!(parse_turns(Some("5-"), 6).unwrap(), (5, 6));
+        assert_eq!(parse_turns(Some("tail=2"), 6).unwrap(), (5, 6));
+        assert_eq!(parse_turns(Some("tail=10"), 6).unwrap(), (1, 6));
+    }
+
+    #[test]
+    fn invalid_turn_ranges() {
+        for spec in ["0", "4-2", "7", "2-9", "x", "1-x", "tail=x"] {
+            assert!(parse_turns(Some(spec), 6).is_err(), "{}", spec);
+        }
+        assert!(parse_turns(None, 0).is_err());
+    }
+
+    #[test]
+    fn included_text_is_held_until_restored() {
+        let dir = project("held", &[("chat.md", ""), ("notes/other.md", OTHER)]);
+        let (messages, held) = include(&dir, &dir, "See @c:notes/other.md#1-2 then answer");
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].content.contains("hand-written"));
+        let restored = held.restore(messages);
+        let content = &restored[0].content;
+        assert!(content.starts_with("See Earlier conversation from "));
+        assert!(content.contains("(turns 1-2 of 3):\n===== BEGIN CONVERSATION =====\nUser:\nWhich parser?\n\nGrok:\nA hand-written one.\n"));
+        assert!(content.ends_with("===== END CONVERSATION =====\n then answer"));
+
+        let (messages, held) = include(&dir, &dir, "@c:notes/other.md#tail=2?as=messages\nGo on");
+        assert_eq!(messages.len(), 3);
+        let restored = held.restore(messages);
+        let turns: Vec<(&str, &str)> = restored.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(turns, [("assistant", "A hand-written one."), ("user", "Why?"), ("user", "\nGo on")]);
+    }
+
+    #[test]
+    fn includes_stay_inside_the_project() {
+        let outside = project("outside", &[("secret.md", OTHER)]);
+        let dir = project("inside", &[("chat.md", "")]);
+        let absolute = format!("@c:{}", outside.join("secret.md").display());
+        let relative = format!("@c:../gchat-include-outside-{}/secret.md", std::process::id());
+        for directive in [absolute, relative] {
+            let (messages, held) = include(&dir, &dir, &directive);
+            assert_eq!(held.restore(messages)[0].content, directive);
+        }
+        // Anything under the project root may be included, even above the chat's own directory
+        let dir = project("above", &[("sub/chat.md", ""), ("other.md", OTHER)]);
+        let (messages, held) = include(&dir.join("sub"), &dir, "@c:../other.md");
+        assert!(held.restore(messages)[0].content.contains("Which parser?"));
+    }
+}
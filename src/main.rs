@@ -1,33 +1,59 @@
+use chrono::{DateTime, Utc};
+use clap::parser::ValueSource;
 use clap::{Arg, Command};
 use regex::Regex;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, Write as IoWrite};
 use std::fmt::Write as FmtWrite;
 use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::time::sleep;
-use walkdir::WalkDir;
-use glob::glob;
 use rodio::{OutputStream, Sink, Source, source::SineWave, Decoder};
 use std::time::Duration as StdDuration;
 use std::io::Cursor;
-use log;
-use dirs;
-use toml;
 
-const GROK_RESPONSE_MARKER: &str = "GROK RESPONSE";
-const USER_PROMPT_MARKER: &str = "USER PROMPT";
+mod archive;
+mod excerpt;
+mod expand;
+mod export;
+mod fork;
+mod git;
+mod include;
+mod outline;
+mod personas;
+mod regen;
+mod render;
+mod search;
+mod shell;
+mod snapshot;
+mod templates;
+mod web;
+
+const PLAIN_USER_MARKER: &str = "USER PROMPT:";
+const PLAIN_GROK_MARKER: &str = "GROK RESPONSE:";
+const HEADING_USER_MARKER: &str = "## 🧑 User";
+const HEADING_GROK_MARKER: &str = "## 🤖 Grok";
 const MAX_LEVEL: u32 = 7;
 
+const API_URL: &str = "https://api.x.ai/v1/chat/completions";
+
 const SYSTEM_INSTRUCTIONS: &str = r#"
 You are Grok, a helpful AI. If you need the contents of files to better answer the user's query, you can request them by responding with EXACTLY this format and NOTHING ELSE:
 GROK REQUESTS FILES: relative/path1, relative/path2
 Paths must be relative to the current working directory (e.g., src/main.rs, not /absolute/path or ../outside). Do not request files outside the project directory. You can request multiple files, directories, or globs (e.g., src/*.rs). The system will automatically include their contents in the next user message. Request all needed files at once if possible. You may request again if more are needed after seeing the contents.
 "#;
 
+// Heads the placeholders appended to a prompt for files Grok asked for
+const REQUESTED_FILES_MARKER: &str = "GROK REQUESTED FILES:";
+
+// Opens the metadata comment written under each response marker
+const RESPONSE_META_PREFIX: &str = "<!-- gchat:";
+
 const DEFAULT_CHAT_FILE: &str = "./gchat.md";
 const DEFAULT_MAX_TOKENS: &str = "L3";
 const DEFAULT_TEMPERATURE: &str = "1.0";
@@ -35,8 +61,10 @@ const DEFAULT_MODEL: &str = "grok-4";
 const DEFAULT_API_TIMEOUT: &str = "600";
 const DEFAULT_AUTO_REQUEST_FILES: bool = false;
 const DEFAULT_AUTO_INCREASE_MAX_TOKENS: bool = false;
+const DEFAULT_MARKER_STYLE: &str = "plain";
+const DEFAULT_ARCHIVE_SUMMARY: bool = false;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 struct Config {
     chat_file: Option<String>,
     max_tokens: Option<String>,
@@ -45,6 +73,410 @@ struct Config {
     api_timeout: Option<u64>,
     auto_request_files: Option<bool>,
     auto_increase_max_tokens: Option<bool>,
+    marker_style: Option<String>,
+    user_marker: Option<String>,
+    grok_marker: Option<String>,
+    archive_max_turns: Option<usize>,
+    archive_max_bytes: Option<u64>,
+    archive_summary: Option<bool>,
+    search_roots: Option<Vec<String>>,
+    max_file_size: Option<String>,
+    max_include_size: Option<String>,
+    include_style: Option<String>,
+    line_numbers: Option<bool>,
+    dedup_includes: Option<bool>,
+    snapshot_includes: Option<bool>,
+    shell_allow: Option<Vec<String>>,
+    shell_timeout: Option<u64>,
+    shell_max_output: Option<String>,
+}
+
+// The lines that open a user prompt and a Grok response in the chat file.
+// Everything that reads or writes the chat file goes through these.
+#[derive(Debug, Clone, PartialEq)]
+struct Markers {
+    user: String,
+    grok: String,
+}
+
+impl Markers {
+    fn from_style(style: &str) -> Result<Markers, String> {
+        match style {
+            "plain" => Ok(Markers {
+                user: PLAIN_USER_MARKER.to_string(),
+                grok: PLAIN_GROK_MARKER.to_string(),
+            }),
+            "heading" => Ok(Markers {
+                user: HEADING_USER_MARKER.to_string(),
+                grok: HEADING_GROK_MARKER.to_string(),
+            }),
+            _ => Err(format!("Unknown marker style '{}': expected 'plain' or 'heading'", style)),
+        }
+    }
+
+    // Configured marker lines must tell turns apart: a blank one would match every
+    // blank line, and identical ones would make every turn a prompt
+    fn validate(&self) -> Result<(), String> {
+        for (name, marker) in [("user_marker", &self.user), ("grok_marker", &self.grok)] {
+            if marker.trim().is_empty() {
+                return Err(format!("{} must not be empty", name));
+            }
+        }
+        if self.user.trim() == self.grok.trim() {
+            return Err(format!("user_marker and grok_marker must differ, but both are '{}'", self.user.trim()));
+        }
+        Ok(())
+    }
+
+    // The role a line opens, if it is a marker line. Trailing whitespace (including a
+    // stray '\r') and up to three spaces of indentation are ignored; anything indented
+    // further is an indented code block, not a marker.
+    fn role_of(&self, line: &str) -> Option<&'static str> {
+        let line = line.trim_end();
+        let unindented = line.trim_start_matches(' ');
+        if line.len() - unindented.len() >= 4 || unindented.starts_with('\t') {
+            return None;
+        }
+        let line = unindented;
+        if line == self.user {
+            Some("user")
+        } else if line == self.grok {
+            Some("assistant")
+        } else {
+            None
+        }
+    }
+}
+
+// Tracks fenced code blocks (``` or ~~~) while scanning a chat file line by line,
+// so marker lines quoted inside code are not mistaken for turn boundaries.
+#[derive(Debug, Default)]
+struct FenceTracker {
+    // Fence character and length of the currently open fence, if any
+    open: Option<(char, usize)>,
+}
+
+impl FenceTracker {
+    // Feed the next line. Returns true if the line belongs to a code block,
+    // including the opening and closing fence lines themselves.
+    fn is_code(&mut self, line: &str) -> bool {
+        let line = line.trim_end();
+        let unindented = line.trim_start_matches(' ');
+        let fence = if line.len() - unindented.len() < 4 {
+            fence_of(unindented)
+        } else {
+            None
+        };
+
+        match (self.open, fence) {
+            (Some((ch, len)), Some((fence_ch, fence_len, info)))
+                if fence_ch == ch && fence_len >= len && info.is_empty() =>
+            {
+                self.open = None;
+                true
+            }
+            (Some(_), _) => true,
+            (None, Some((fence_ch, fence_len, info))) => {
+                // A backtick fence's info string may not itself contain backticks
+                if fence_ch == '`' && info.contains('`') {
+                    return false;
+                }
+                self.open = Some((fence_ch, fence_len));
+                true
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+// Split a fence line into its character, length and info string
+fn fence_of(line: &str) -> Option<(char, usize, &str)> {
+    let ch = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|c| *c == ch).count();
+    if len < 3 {
+        return None;
+    }
+    Some((ch, len, line[len..].trim()))
+}
+
+// Close any code fence a response leaves open (e.g. when truncated), so the
+// following marker line is not swallowed by the unterminated block.
+fn close_open_fence(text: &str) -> String {
+    let mut fences = FenceTracker::default();
+    for line in text.lines() {
+        fences.is_code(line);
+    }
+    match fences.open {
+        Some((ch, len)) => format!("{}\n{}", text, ch.to_string().repeat(len)),
+        None => text.to_string(),
+    }
+}
+
+// Split a chat file into lines (keeping their terminators), tagging each marker
+// line with the role it opens and each line inside a fenced code block. A leading
+// BOM is ignored when matching markers, as are marker lines inside code blocks.
+fn scan_chat_lines<'a>(content: &'a str, markers: &Markers) -> Vec<(&'a str, Option<&'static str>, bool)> {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let texts: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| if i == 0 { line.trim_start_matches('\u{feff}') } else { line })
+        .collect();
+
+    code_lines(&texts, |line| markers.role_of(line).is_some())
+        .into_iter()
+        .zip(lines.into_iter().zip(texts))
+        .map(|(in_code, (line, text))| (line, if in_code { None } else { markers.role_of(text) }, in_code))
+        .collect()
+}
+
+// Which of a text's lines belong to a fenced code block. Fences must balance: if
+// one is left open at the end of the text, a fence line is read as ordinary text
+// instead, either the one left open or the opener of a block hiding marker lines,
+// whichever leaves the fewest marker lines hidden. So a stray ``` in a prompt can't
+// hide every marker line after it, nor pair up with a fence in the response.
+fn code_lines(lines: &[&str], is_marker: impl Fn(&str) -> bool) -> Vec<bool> {
+    let hidden = |in_code: &[bool]| lines.iter().zip(in_code).filter(|(line, code)| **code && is_marker(line)).count();
+    let mut ignored = Vec::new();
+    loop {
+        let (in_code, blocks, unclosed) = scan_fences(lines, &ignored);
+        let Some(unclosed) = unclosed else {
+            return in_code;
+        };
+        let candidates = std::iter::once(unclosed).chain(
+            blocks
+                .into_iter()
+                .filter(|block| lines[block.clone()].iter().any(|line| is_marker(line)))
+                .map(|block| block.start),
+        );
+        let best = candidates
+            .min_by_key(|&candidate| {
+                let trial: Vec<usize> = ignored.iter().copied().chain([candidate]).collect();
+                hidden(&scan_fences(lines, &trial).0)
+            })
+            .expect("the open fence is a candidate");
+        ignored.push(best);
+    }
+}
+
+// Scan for fenced code blocks, reading the fence lines in `ignored` as text: which
+// lines are code, the lines of each closed block, and where a block left open starts
+fn scan_fences(lines: &[&str], ignored: &[usize]) -> (Vec<bool>, Vec<std::ops::Range<usize>>, Option<usize>) {
+    let mut fences = FenceTracker::default();
+    let mut in_code = Vec::with_capacity(lines.len());
+    let mut blocks = Vec::new();
+    let mut opened_at = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if ignored.contains(&i) {
+            in_code.push(false);
+            continue;
+        }
+        let was_open = fences.open.is_some();
+        in_code.push(fences.is_code(line));
+        match (was_open, fences.open.is_some()) {
+            (false, true) => opened_at = i,
+            (true, false) => blocks.push(opened_at..i + 1),
+            _ => {}
+        }
+    }
+    (in_code, blocks, fences.open.map(|_| opened_at))
+}
+
+// Split a chat file body into its marked sections: the role each opens and the
+// byte range from its marker line to the next marker. Text before the first
+// marker is not part of any section.
+fn chat_sections(content: &str, markers: &Markers) -> Vec<(&'static str, std::ops::Range<usize>)> {
+    let mut sections: Vec<(&'static str, std::ops::Range<usize>)> = Vec::new();
+    let mut offset = 0;
+
+    for (line, role, _) in scan_chat_lines(content, markers) {
+        if let Some(role) = role {
+            if let Some(last) = sections.last_mut() {
+                last.1.end = offset;
+            }
+            sections.push((role, offset..content.len()));
+        }
+        offset += line.len();
+    }
+
+    sections
+}
+
+// Escape lines of a response that would otherwise be read back as marker lines,
+// by prefixing a backslash. Lines already escaped get one more backslash, so the
+// escaping is reversed exactly by `unescape_marker_line`. Lines in code blocks
+// are never markers and are left alone.
+fn escape_markers(text: &str, markers: &Markers) -> String {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut escaped = String::with_capacity(text.len());
+
+    for (line, in_code) in lines.iter().zip(code_lines(&lines, |line| markers.role_of(line).is_some())) {
+        if !in_code && markers.role_of(line.trim_start_matches([' ', '\\'])).is_some() {
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            escaped.push_str(&line[..indent]);
+            escaped.push('\\');
+            escaped.push_str(&line[indent..]);
+        } else {
+            escaped.push_str(line);
+        }
+    }
+
+    escaped
+}
+
+// Undo `escape_markers` for a single (non-code) line of the chat file
+fn unescape_marker_line<'a>(line: &'a str, markers: &Markers) -> Cow<'a, str> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    if rest.starts_with('\\') && markers.role_of(rest.trim_start_matches('\\')).is_some() {
+        Cow::Owned(format!("{}{}", &line[..indent], &rest[1..]))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+// The resolved settings a chat file is processed with
+#[derive(Debug, Clone)]
+struct Settings {
+    default_level: u32,
+    temperature: f32,
+    api_timeout: u64,
+    auto_request_files: bool,
+    auto_increase_max_tokens: bool,
+    model: String,
+    markers: Markers,
+    system_prompt: Option<String>,
+    archive: archive::ArchivePolicy,
+    budget: expand::Budget,
+    rendering: render::Rendering,
+    dedup_includes: bool,
+    snapshot_includes: bool,
+    shell: shell::ShellPolicy,
+    sources: SettingSources,
+}
+
+// Where each per-chat setting came from (default, config, CLI, front matter,
+// persona or directive), reported on every send
+#[derive(Debug, Clone)]
+struct SettingSources {
+    model: String,
+    temperature: String,
+    max_tokens: String,
+    system_prompt: String,
+    auto_request_files: String,
+    auto_increase_max_tokens: String,
+}
+
+impl Settings {
+    // Layer front matter (a chat file's, or a persona's) over these settings
+    fn with_front_matter(&self, front_matter: &FrontMatter, source: &str) -> Result<Settings, String> {
+        let mut settings = self.clone();
+        let sources = &mut settings.sources;
+        if let Some(model) = &front_matter.model {
+            settings.model = model.clone();
+            sources.model = source.to_string();
+        }
+        if let Some(temperature) = front_matter.temperature {
+            settings.temperature = temperature;
+            sources.temperature = source.to_string();
+        }
+        if let Some(max_tokens) = &front_matter.max_tokens {
+            settings.default_level = get_level_from_str(max_tokens)?;
+            sources.max_tokens = source.to_string();
+        }
+        if let Some(system) = &front_matter.system {
+            settings.system_prompt = Some(system.clone());
+            sources.system_prompt = source.to_string();
+        }
+        if let Some(auto_request_files) = front_matter.auto_request_files {
+            settings.auto_request_files = auto_request_files;
+            sources.auto_request_files = source.to_string();
+        }
+        if let Some(auto_increase_max_tokens) = front_matter.auto_increase_max_tokens {
+            settings.auto_increase_max_tokens = auto_increase_max_tokens;
+            sources.auto_increase_max_tokens = source.to_string();
+        }
+        Ok(settings)
+    }
+
+    // The settings a send uses, and where each came from
+    fn report(&self, level: u32, level_source: &str, temperature: f32, temperature_source: &str) -> String {
+        let sources = &self.sources;
+        let system_prompt = match &self.system_prompt {
+            Some(_) => format!("set ({})", sources.system_prompt),
+            None => "none".to_string(),
+        };
+        let mut report = String::from("Settings for this send:\n");
+        writeln!(report, "  Model: {} ({})", self.model, sources.model).expect("Failed to write to String");
+        writeln!(report, "  Max tokens: {} (L{}, {})", parse_level(level), level, level_source).expect("Failed to write to String");
+        writeln!(report, "  Temperature: {} ({})", temperature, temperature_source).expect("Failed to write to String");
+        writeln!(report, "  System prompt: {}", system_prompt).expect("Failed to write to String");
+        writeln!(report, "  Auto request files: {} ({})", self.auto_request_files, sources.auto_request_files)
+            .expect("Failed to write to String");
+        writeln!(report, "  Auto increase max tokens: {} ({})", self.auto_increase_max_tokens, sources.auto_increase_max_tokens)
+            .expect("Failed to write to String");
+        report
+    }
+}
+
+// Remove a setting directive (`@t`, `@p`) from every user message, returning the
+// value in effect for this turn and where it came from. A plain directive (`@p:0.2`)
+// applies only to the prompt it's in, so only the last prompt's counts; a sticky one
+// (`@p!:0.2`) applies from its prompt on, until another sticky one replaces it.
+// The regex captures the `!` and the value.
+fn take_setting_directive(messages: &mut [Message], re: &Regex, name: &str) -> Option<(String, String)> {
+    let last = messages.len().saturating_sub(1);
+    let mut sticky = None;
+    let mut one_shot = None;
+    for (i, msg) in messages.iter_mut().enumerate().filter(|(_, m)| m.role == "user") {
+        for cap in re.captures_iter(&msg.content) {
+            let value = cap[2].to_string();
+            if !cap[1].is_empty() {
+                let source = if i == last {
+                    format!("sticky @{}!: in this prompt", name)
+                } else {
+                    format!("sticky @{}!: in turn {}", name, i + 1)
+                };
+                sticky = Some((value, source));
+            } else if i == last {
+                one_shot = Some((value, format!("@{}: in this prompt", name)));
+            }
+        }
+        msg.content = re.replace_all(&msg.content, "").to_string();
+    }
+    one_shot.or(sticky)
+}
+
+// Optional per-chat settings from a front-matter block at the top of the chat file,
+// either TOML between `+++` lines or YAML between `---` lines. These override the
+// CLI and config file, and are themselves overridden by inline directives. Unknown
+// keys are an error, so a misspelt setting isn't silently ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FrontMatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    // A persona from the persona library, used unless an `@persona:` directive picks another
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persona: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_request_files: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_increase_max_tokens: Option<bool>,
+    // Placeholders (e.g. "@f:./src") included ahead of the first user prompt
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    context: Vec<String>,
+    // The chat file this one was forked from, and the number of turns copied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forked_at: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -64,6 +496,58 @@ struct ChatRequest {
 #[derive(Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Usage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+}
+
+// Details of how a response was produced, written as a comment line under its
+// marker so the chat file records them. Comments are never sent to the API.
+#[derive(Debug, Clone)]
+struct ResponseMeta {
+    time: DateTime<Utc>,
+    model: String,
+    temperature: f32,
+    max_tokens: u32,
+    usage: Option<Usage>,
+    latency: Duration,
+    finish_reason: Option<String>,
+    retries: u32,
+}
+
+impl ResponseMeta {
+    fn to_comment(&self) -> String {
+        let mut line = format!(
+            "{} time={} model={} temperature={} max_tokens={}",
+            RESPONSE_META_PREFIX,
+            self.time.format("%Y-%m-%dT%H:%M:%SZ"),
+            self.model,
+            self.temperature,
+            self.max_tokens
+        );
+        if let Some(usage) = &self.usage {
+            if let Some(prompt_tokens) = usage.prompt_tokens {
+                write!(line, " prompt_tokens={}", prompt_tokens).expect("Failed to write to String");
+            }
+            if let Some(completion_tokens) = usage.completion_tokens {
+                write!(line, " completion_tokens={}", completion_tokens).expect("Failed to write to String");
+            }
+        }
+        write!(
+            line,
+            " latency_ms={} finish_reason={} retries={} -->",
+            self.latency.as_millis(),
+            self.finish_reason.as_deref().unwrap_or("unknown"),
+            self.retries
+        )
+        .expect("Failed to write to String");
+        line
+    }
 }
 
 #[derive(Deserialize)]
@@ -76,15 +560,7 @@ struct Choice {
 async fn main() -> io::Result<()> {
     env_logger::init();
 
-    let mut config: Config = Config {
-        chat_file: None,
-        max_tokens: None,
-        temperature: None,
-        model: None,
-        api_timeout: None,
-        auto_request_files: None,
-        auto_increase_max_tokens: None,
-    };
+    let mut config = Config::default();
     if let Some(config_dir) = dirs::config_dir() {
         let config_path = config_dir.join("gchat/config.toml");
         if config_path.exists() {
@@ -93,9 +569,9 @@ async fn main() -> io::Result<()> {
                 eprintln!("Error parsing config file {}: {}", config_path.display(), e);
                 io::Error::new(io::ErrorKind::InvalidData, e)
             })?;
-            println!("Loaded config from {}", config_path.display());
+            eprintln!("Loaded config from {}", config_path.display());
         } else {
-            println!("No config file found at {}", config_path.display());
+            eprintln!("No config file found at {}", config_path.display());
         }
     }
 
@@ -150,10 +626,241 @@ async fn main() -> io::Result<()> {
                 .long("auto-increase-max-tokens")
                 .help("Automatically increase max_tokens on truncation")
                 .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("archive_max_turns")
+                .long("archive-max-turns")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Move older turns to an archive file once the chat has more than N turns"),
+        )
+        .arg(
+            Arg::new("archive_max_bytes")
+                .long("archive-max-bytes")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .help("Move older turns to an archive file once the chat is larger than BYTES"),
+        )
+        .arg(
+            Arg::new("archive_summary")
+                .long("archive-summary")
+                .help("Keep a Grok-written summary of archived turns in the chat file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_file_size")
+                .long("max-file-size")
+                .value_name("SIZE")
+                .help("Most of any one file to include in a prompt, e.g. 40k (bytes) or 10kt (tokens)"),
+        )
+        .arg(
+            Arg::new("max_include_size")
+                .long("max-include-size")
+                .value_name("SIZE")
+                .help("Most any one @f/@d placeholder may include, e.g. 200k (bytes) or 50kt (tokens)"),
+        )
+        .arg(
+            Arg::new("include_style")
+                .long("include-style")
+                .value_name("STYLE")
+                .value_parser(["fenced", "xml"])
+                .help("How included files are laid out: language-tagged Markdown fences (default) or <file path=...> tags"),
+        )
+        .arg(
+            Arg::new("line_numbers")
+                .long("line-numbers")
+                .help("Number the lines of included files")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedup_includes")
+                .long("dedup-includes")
+                .help("Send each included file once per conversation; later includes of it become a reference, or a diff if it changed")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("snapshot_includes")
+                .long("snapshot-includes")
+                .help("Freeze the files each prompt includes when it's first sent, and resend those copies (refresh with @refresh)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list-templates")
+                .long("list-templates")
+                .help("List the prompt templates available to @tpl: directives, and exit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("markers")
+                .long("markers")
+                .value_name("STYLE")
+                .help("Section marker style: 'plain' (USER PROMPT:) or 'heading' (## 🧑 User)"),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Export a chat file as JSON, JSONL or HTML")
+                .arg(
+                    Arg::new("file")
+                        .value_name("PATH")
+                        .help("Chat file to export (defaults to the configured chat file)"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("json")
+                        .value_parser(["json", "jsonl", "html"])
+                        .help("Output format"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PATH")
+                        .help("Write to this file instead of standard output"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Create a chat file from an OpenAI-style JSON (or JSONL) conversation")
+                .arg(
+                    Arg::new("source")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("Conversation to import"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("PATH")
+                        .help("Path of the new chat file (defaults to the source with a .md extension)"),
+                ),
+        )
+        .subcommand(
+            Command::new("fork")
+                .about("Copy the first turns of a chat file into a new chat file")
+                .arg(
+                    Arg::new("file")
+                        .value_name("PATH")
+                        .required(true)
+                        .help("Chat file to fork"),
+                )
+                .arg(
+                    Arg::new("at")
+                        .long("at")
+                        .value_name("N")
+                        .required(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Number of turns to copy"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("PATH")
+                        .help("Path of the new chat file (defaults to <name>.fork<N>.md next to the original)"),
+                ),
+        )
+        .subcommand(
+            Command::new("regen")
+                .about("Regenerate the last response of a chat file, keeping the old one as an alternative")
+                .arg(
+                    Arg::new("file")
+                        .value_name("PATH")
+                        .help("Chat file (defaults to the configured chat file)"),
+                ),
+        )
+        .subcommand(
+            Command::new("search")
+                .about("Search all chat files and archives under the search roots")
+                .arg(
+                    Arg::new("query")
+                        .value_name("QUERY")
+                        .required(true)
+                        .num_args(1..)
+                        .help("Words to search for (turns must contain all of them)"),
+                )
+                .arg(
+                    Arg::new("role")
+                        .long("role")
+                        .value_name("ROLE")
+                        .value_parser(["user", "assistant"])
+                        .help("Only match turns from this role"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("DATE")
+                        .help("Only search chat files modified on or after DATE (YYYY-MM-DD)"),
+                )
+                .arg(
+                    Arg::new("root")
+                        .long("root")
+                        .value_name("DIR")
+                        .action(clap::ArgAction::Append)
+                        .help("Directory to search (repeatable; defaults to search_roots in the config, or the current directory)"),
+                )
+                .arg(
+                    Arg::new("reindex")
+                        .long("reindex")
+                        .help("Rebuild the search index from scratch")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("tree")
+                .about("Show the family of forks a chat file belongs to")
+                .arg(
+                    Arg::new("file")
+                        .value_name("PATH")
+                        .help("Chat file (defaults to the configured chat file)"),
+                ),
+        )
+        .subcommand(Command::new("personas").about("List the personas available to @persona: directives"))
+        .subcommand(
+            Command::new("convert")
+                .about("Rewrite the section markers of a chat file in another style")
+                .arg(
+                    Arg::new("file")
+                        .value_name("PATH")
+                        .help("Chat file to convert (defaults to the configured chat file)"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("STYLE")
+                        .required(true)
+                        .help("Marker style to convert to"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("STYLE")
+                        .help("Marker style the file currently uses (defaults to the configured markers)"),
+                ),
         );
 
     let matches = app.get_matches();
 
+    // Where a value comes from, before the config's values are taken below
+    let source_of = |id: &str, in_config: bool| {
+        if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            "CLI"
+        } else if in_config {
+            "config"
+        } else {
+            "default"
+        }
+        .to_string()
+    };
+    let sources = SettingSources {
+        model: source_of("model", config.model.is_some()),
+        temperature: source_of("temperature", config.temperature.is_some()),
+        max_tokens: source_of("max_tokens", config.max_tokens.is_some()),
+        system_prompt: "default".to_string(),
+        auto_request_files: source_of("auto_request_files", config.auto_request_files.is_some()),
+        auto_increase_max_tokens: source_of("auto_increase_max_tokens", config.auto_increase_max_tokens.is_some()),
+    };
+
     // Extract final values: CLI overrides config overrides defaults
     let chat_file = if matches.contains_id("chat_file") {
         matches.get_one::<String>("chat_file").unwrap().clone()
@@ -185,18 +892,90 @@ async fn main() -> io::Result<()> {
         config.api_timeout.unwrap_or(DEFAULT_API_TIMEOUT.parse::<u64>().unwrap())
     };
 
-    let auto_request_files = if matches.contains_id("auto_request_files") {
+    let auto_request_files = if matches.get_flag("auto_request_files") {
         true
     } else {
         config.auto_request_files.unwrap_or(DEFAULT_AUTO_REQUEST_FILES)
     };
 
-    let auto_increase_max_tokens = if matches.contains_id("auto_increase_max_tokens") {
+    let auto_increase_max_tokens = if matches.get_flag("auto_increase_max_tokens") {
         true
     } else {
         config.auto_increase_max_tokens.unwrap_or(DEFAULT_AUTO_INCREASE_MAX_TOKENS)
     };
 
+    let marker_style = if matches.contains_id("markers") {
+        matches.get_one::<String>("markers").unwrap().clone()
+    } else {
+        config.marker_style.unwrap_or(DEFAULT_MARKER_STYLE.to_string())
+    };
+    let mut markers = match Markers::from_style(&marker_style) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Error parsing markers: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Explicit marker lines in the config override the chosen style. Marker lines are
+    // matched without surrounding whitespace, so it's dropped here too.
+    if let Some(user_marker) = config.user_marker {
+        markers.user = user_marker.trim().to_string();
+    }
+    if let Some(grok_marker) = config.grok_marker {
+        markers.grok = grok_marker.trim().to_string();
+    }
+    if let Err(e) = markers.validate() {
+        eprintln!("Error parsing markers: {}", e);
+        std::process::exit(1);
+    }
+
+    let archive = archive::ArchivePolicy {
+        max_turns: matches.get_one::<usize>("archive_max_turns").copied().or(config.archive_max_turns),
+        max_bytes: matches.get_one::<u64>("archive_max_bytes").copied().or(config.archive_max_bytes),
+        summary: matches.get_flag("archive_summary") || config.archive_summary.unwrap_or(DEFAULT_ARCHIVE_SUMMARY),
+    };
+
+    let parse_budget = |arg: &str, configured: Option<String>| {
+        let value = matches.get_one::<String>(arg).cloned().or(configured)?;
+        match expand::parse_size(&value) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                eprintln!("Error parsing {}: {}", arg, e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let budget = expand::Budget {
+        per_file: parse_budget("max_file_size", config.max_file_size),
+        per_placeholder: parse_budget("max_include_size", config.max_include_size),
+    };
+
+    let include_style = matches.get_one::<String>("include_style").cloned().or(config.include_style);
+    let rendering = render::Rendering {
+        style: match include_style.as_deref().map(render::IncludeStyle::from_name).transpose() {
+            Ok(style) => style.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("Error parsing include_style: {}", e);
+                std::process::exit(1);
+            }
+        },
+        line_numbers: matches.get_flag("line_numbers") || config.line_numbers.unwrap_or(false),
+    };
+    let dedup_includes = matches.get_flag("dedup_includes") || config.dedup_includes.unwrap_or(false);
+    let snapshot_includes = matches.get_flag("snapshot_includes") || config.snapshot_includes.unwrap_or(false);
+
+    let shell = shell::ShellPolicy {
+        allow: config.shell_allow.unwrap_or_default(),
+        timeout: config.shell_timeout.unwrap_or(shell::DEFAULT_TIMEOUT),
+        max_output: match config.shell_max_output.as_deref().map(expand::parse_size).transpose() {
+            Ok(max_output) => max_output.unwrap_or(shell::DEFAULT_MAX_OUTPUT),
+            Err(e) => {
+                eprintln!("Error parsing shell_max_output: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
     // Parse the default level and max_tokens (using the final max_tokens_str)
     let default_level = match get_level_from_str(&max_tokens_str) {
         Ok(v) => v,
@@ -207,45 +986,164 @@ async fn main() -> io::Result<()> {
     };
     let default_max_tokens = 512u32 << default_level;
 
-    let chat_path = PathBuf::from(&chat_file);
+    let mut chat_path = PathBuf::from(&chat_file);
+
+    if let Some(("convert", sub_matches)) = matches.subcommand() {
+        let path = sub_matches.get_one::<String>("file").map(PathBuf::from).unwrap_or(chat_path);
+        let from = match sub_matches.get_one::<String>("from") {
+            Some(style) => Markers::from_style(style),
+            None => Ok(markers),
+        };
+        let to = Markers::from_style(sub_matches.get_one::<String>("to").unwrap());
+        let (from, to) = match (from, to) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Error parsing markers: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let count = convert_markers(&path, &from, &to)?;
+        println!("Converted {} marker lines in {}", count, path.display());
+        return Ok(());
+    }
+
+    if matches.get_flag("list-templates") {
+        print!("{}", templates::describe_templates()?);
+        return Ok(());
+    }
+
+    match matches.subcommand() {
+        Some(("export", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("file").map(PathBuf::from).unwrap_or(chat_path);
+            let format = sub_matches.get_one::<String>("format").unwrap();
+            let exported = export::export_chat(&path, format, &markers)?;
+            match sub_matches.get_one::<String>("output") {
+                Some(output) => {
+                    fs::write(output, exported)?;
+                    eprintln!("Exported {} to {}", path.display(), output);
+                }
+                None => print!("{}", exported),
+            }
+            return Ok(());
+        }
+        Some(("import", sub_matches)) => {
+            let source = PathBuf::from(sub_matches.get_one::<String>("source").unwrap());
+            let to = sub_matches.get_one::<String>("to").map(PathBuf::from);
+            let imported = export::import_chat(&source, to, &markers)?;
+            println!("Imported {} into {}", source.display(), imported.display());
+            return Ok(());
+        }
+        Some(("fork", sub_matches)) => {
+            let path = PathBuf::from(sub_matches.get_one::<String>("file").unwrap());
+            let at = *sub_matches.get_one::<usize>("at").unwrap();
+            let to = sub_matches.get_one::<String>("to").map(PathBuf::from);
+            let fork_path = fork::fork_chat(&path, at, to, &markers)?;
+            println!("Forked {} at turn {} into {}", path.display(), at, fork_path.display());
+            return Ok(());
+        }
+        Some(("regen", sub_matches)) => {
+            if let Some(file) = sub_matches.get_one::<String>("file") {
+                chat_path = PathBuf::from(file);
+            }
+            regen::request_regen(&chat_path, &markers)?;
+        }
+        Some(("search", sub_matches)) => {
+            let query: Vec<&str> = sub_matches.get_many::<String>("query").unwrap().map(|q| q.as_str()).collect();
+            let roots: Vec<PathBuf> = match sub_matches.get_many::<String>("root") {
+                Some(roots) => roots.map(PathBuf::from).collect(),
+                None => config.search_roots.unwrap_or(vec![".".to_string()]).iter().map(PathBuf::from).collect(),
+            };
+            let since = match sub_matches.get_one::<String>("since").map(|s| search::parse_since(s)).transpose() {
+                Ok(since) => since,
+                Err(e) => {
+                    eprintln!("Error parsing --since: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let options = search::SearchOptions {
+                roots,
+                role: sub_matches.get_one::<String>("role").cloned(),
+                since,
+                reindex: sub_matches.get_flag("reindex"),
+            };
+            print!("{}", search::search(&query.join(" "), &options, &markers)?);
+            return Ok(());
+        }
+        Some(("tree", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("file").map(PathBuf::from).unwrap_or(chat_path);
+            print!("{}", fork::fork_tree(&path, &markers)?);
+            return Ok(());
+        }
+        Some(("personas", _)) => {
+            print!("{}", personas::describe_personas()?);
+            return Ok(());
+        }
+        _ => {}
+    }
 
     // Create chat file if it doesn't exist
     if !chat_path.exists() {
         let mut file = File::create(&chat_path)?;
-        writeln!(file, "{}:\n", USER_PROMPT_MARKER)?;
+        writeln!(file, "{}\n", markers.user)?;
         println!(
-            "Created chat file at {}. Start your conversation by adding:\n{}:\nYour prompt here\n",
-            chat_path.display(), USER_PROMPT_MARKER
+            "Created chat file at {}. Start your conversation by adding:\n{}\nYour prompt here\n",
+            chat_path.display(), markers.user
         );
     }
 
     // Print settings on startup
     println!("Running with settings:");
-    println!("  Chat file: {}", chat_file);
+    println!("  Chat file: {}", chat_path.display());
     println!("  Max tokens: {} ({})", max_tokens_str, default_max_tokens);
     println!("  Temperature: {}", temperature);
     println!("  API model: {}", model);
     println!("  API timeout: {} seconds", api_timeout);
     println!("  Auto request files: {}", auto_request_files);
     println!("  Auto increase max tokens: {}", auto_increase_max_tokens);
-
-    println!("App started. Polling {} for changes every 1 second.", chat_file);
-
-    // Initial process on startup
-    if let Err(e) = process_chat_file(
-        &chat_path,
+    println!("  Markers: {} / {}", markers.user, markers.grok);
+    println!("  Archive: {}", archive);
+    println!("  Include budget: {}", budget);
+    println!("  Include style: {}", rendering);
+    println!(
+        "  Repeated includes: {}",
+        if dedup_includes { "sent once, then as a reference or diff" } else { "sent in full" }
+    );
+    println!(
+        "  Include snapshots: {}",
+        if snapshot_includes { "on (in .gchat/snapshots)" } else { "off" }
+    );
+    println!("  Shell commands: {}", shell);
+
+    let settings = Settings {
         default_level,
         temperature,
         api_timeout,
         auto_request_files,
         auto_increase_max_tokens,
-        &model,
-    )
-    .await
-    {
+        model,
+        markers,
+        system_prompt: None,
+        archive,
+        budget,
+        rendering,
+        dedup_includes,
+        snapshot_includes,
+        shell,
+        sources,
+    };
+
+    println!("App started. Polling {} for changes every 1 second.", chat_path.display());
+
+    // Initial process on startup
+    if let Err(e) = process_chat_file(&chat_path, &settings).await {
         println!("Processing error: {}", e);
     }
 
+    // `gchat regen` is a one-off
+    if let Some(("regen", _)) = matches.subcommand() {
+        return Ok(());
+    }
+
     // Get initial modification time (or now if unavailable)
     let mut last_mtime = fs::metadata(&chat_path)
         .and_then(|meta| meta.modified())
@@ -267,17 +1165,7 @@ async fn main() -> io::Result<()> {
 
         if current_mtime > last_mtime {
             // File changed: process it
-            if let Err(e) = process_chat_file(
-                &chat_path,
-                default_level,
-                temperature,
-                api_timeout,
-                auto_request_files,
-                auto_increase_max_tokens,
-                &model,
-            )
-            .await
-            {
+            if let Err(e) = process_chat_file(&chat_path, &settings).await {
                 println!("Processing error: {}", e);
             }
             // Update last mtime after processing
@@ -310,147 +1198,183 @@ fn parse_level(level: u32) -> u32 {
 
 async fn process_chat_file(
     chat_path: &PathBuf,
-    default_level: u32,
-    default_temperature: f32,
-    api_timeout: u64,
-    auto_request_files: bool,
-    auto_increase_max_tokens: bool,
-    model: &str,
+    settings: &Settings,
 ) -> io::Result<()> {
     // Short debounce to ensure save is complete (helps with atomic saves)
     sleep(Duration::from_millis(500)).await;
 
+    let re_t = Regex::new(r"@t(!?)\s*:\s*L(\d+)").unwrap();
+    let re_p = Regex::new(r"@p(!?)\s*:\s*(\d*\.?\d+)").unwrap();
+    let re_persona = Regex::new(r"@persona\s*:\s*([\w.-]+)").unwrap();
+
     // Outer loop to handle chained file requests (which modify the file)
     loop {
         let content = fs::read_to_string(chat_path)?;
-        let mut messages = parse_chat_messages(&content);
+
+        // To regenerate, resend the history up to (not including) the last response
+        let regen = regen::find_regen(&content, &settings.markers);
+        let history = regen.as_ref().map_or(content.as_str(), |r| &content[..r.response_start]);
+        let mut messages = parse_chat_messages(history, &settings.markers);
 
         if messages.is_empty() || messages.last().unwrap().role != "user" || messages.last().unwrap().content.trim().is_empty() {
             println!("No complete user prompt to process in chat file.");
             return Ok(()); // No send needed
         }
+        if regen.is_some() {
+            println!("Regenerating the last response.");
+        }
 
-        // Handle @t placeholders: remove from all user messages, and track the last @t across all user messages
-        let re_t = Regex::new(r"@t\s*:\s*L(\d+)").unwrap();
-        let mut persistent_level: Option<u32> = None;
-        for i in 0..messages.len() {
-            if messages[i].role == "user" {
-                let content = &messages[i].content;
-                let mut new_content = content.to_string();
-                let mut last_level: Option<u32> = None;
-                let mut ranges = vec![];
-                for cap in re_t.captures_iter(content) {
-                    let whole = cap.get(0).unwrap();
-                    ranges.push(whole.range());
-                    if let Some(num_str) = cap.get(1) {
-                        if let Ok(lvl) = num_str.as_str().parse::<u32>() {
-                            last_level = Some(lvl);
-                        }
-                    }
-                }
-                // Remove in reverse order to avoid index issues
-                for range in ranges.into_iter().rev() {
-                    new_content.replace_range(range, "");
-                }
-                messages[i].content = new_content;
-                // Update persistent_level if this message had a @t
-                if let Some(lvl) = last_level {
-                    persistent_level = Some(lvl);
-                }
-            }
+        // Expand templates first, so the directives and placeholders in them take effect
+        let last = messages.len() - 1;
+        for (i, msg) in messages.iter_mut().enumerate().filter(|(_, m)| m.role == "user") {
+            msg.content = templates::expand_templates(&msg.content, i == last)?;
         }
 
-        // Set current_level based on persistent or default, with capping if needed
-        let mut current_level = default_level;
-        if let Some(lvl) = persistent_level {
-            current_level = lvl;
-            if current_level > MAX_LEVEL {
-                println!(
-                    "Warning: Specified level L{} too high, capping at L{} ({} tokens)",
-                    lvl,
-                    MAX_LEVEL,
-                    512u32 << MAX_LEVEL
-                );
-                current_level = MAX_LEVEL;
+        // Handle @persona directives: remove from all user messages; the last one wins,
+        // falling back to the persona named in the front matter
+        let front_matter = parse_front_matter(&content, Some(&settings.markers))?.unwrap_or_default();
+        let mut persona_name = front_matter.persona.clone();
+        let mut persona_directive = false;
+        for msg in messages.iter_mut().filter(|m| m.role == "user") {
+            if let Some(cap) = re_persona.captures_iter(&msg.content).last() {
+                persona_name = Some(cap[1].to_string());
+                persona_directive = true;
             }
-            println!("Setting `max_tokens` API parameter to {}", parse_level(current_level));
-        }
-
-        // Handle @p placeholders: similar to @t, remove from all user messages, track the last @p across all user messages
-        let mut local_temperature = default_temperature;
-        let re_p = Regex::new(r"@p\s*:\s*(\d*\.?\d+)").unwrap();
-        let mut persistent_temperature: Option<f32> = None;
-        for i in 0..messages.len() {
-            if messages[i].role == "user" {
-                let content = &messages[i].content;
-                let mut new_content = content.to_string();
-                let mut last_temp: Option<f32> = None;
-                let mut ranges = vec![];
-                for cap in re_p.captures_iter(content) {
-                    let whole = cap.get(0).unwrap();
-                    ranges.push(whole.range());
-                    if let Some(num_str) = cap.get(1) {
-                        if let Ok(temp) = num_str.as_str().parse::<f32>() {
-                            last_temp = Some(temp);
-                        }
-                    }
-                }
-                // Remove in reverse order to avoid index issues
-                for range in ranges.into_iter().rev() {
-                    new_content.replace_range(range, "");
-                }
-                messages[i].content = new_content;
-                // Update persistent_temperature if this message had a @p
-                if let Some(temp) = last_temp {
-                    persistent_temperature = Some(temp);
+            msg.content = re_persona.replace_all(&msg.content, "").to_string();
+        }
+        let persona = persona_name.as_deref().map(personas::load_persona).transpose()?;
+
+        // Layer the persona's defaults and the chat file's front matter (if any) between
+        // the CLI and inline directives. A persona named in the front matter is its
+        // default, so the front matter overrides it; an `@persona:` directive is inline,
+        // so it overrides the front matter.
+        let mut context = Vec::new();
+        let mut settings = settings.clone();
+        let with_front_matter = |settings: &Settings| {
+            settings
+                .with_front_matter(&front_matter, "front matter")
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid front matter: {}", e)))
+        };
+        if persona_directive {
+            settings = with_front_matter(&settings)?;
+        }
+        if let Some(persona) = &persona {
+            let defaults = FrontMatter {
+                system: Some(persona.prompt.clone()).filter(|p| !p.is_empty()),
+                ..persona.front_matter.clone()
+            };
+            settings = settings
+                .with_front_matter(&defaults, &format!("persona '{}'", persona.name))
+                .map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid persona {}: {}", persona.path.display(), e))
+                })?;
+            context.extend(defaults.context);
+        }
+        if !persona_directive {
+            settings = with_front_matter(&settings)?;
+        }
+        let settings = &settings;
+        context.extend(front_matter.context.iter().cloned());
+        // Context goes ahead of the first prompt, after any archive summary
+        if let Some((i, first)) = messages.iter_mut().enumerate().find(|(_, m)| m.role == "user").filter(|_| !context.is_empty()) {
+            let context = templates::expand_templates(&context.join("\n"), i == last)?;
+            first.content = format!("{}\n\n{}", context, first.content);
+        }
+
+        // Handle @t and @p directives: one-shot in the current prompt, or sticky from an earlier one
+        let mut current_level = settings.default_level;
+        let mut level_source = settings.sources.max_tokens.clone();
+        if let Some((value, source)) = take_setting_directive(&mut messages, &re_t, "t") {
+            if let Ok(lvl) = value.parse::<u32>() {
+                current_level = lvl;
+                level_source = source;
+                if current_level > MAX_LEVEL {
+                    println!(
+                        "Warning: Specified level L{} too high, capping at L{} ({} tokens)",
+                        lvl,
+                        MAX_LEVEL,
+                        512u32 << MAX_LEVEL
+                    );
+                    current_level = MAX_LEVEL;
                 }
             }
         }
-        // After processing all messages, apply the last seen temperature if any
-        if let Some(temp) = persistent_temperature {
-            local_temperature = temp;
-            // Optional: Clamp to reasonable range (e.g., 0.0 to 2.0)
-            if local_temperature < 0.0 || local_temperature > 2.0 {
-                println!(
-                    "Warning: Specified temperature {} is outside typical range (0.0-2.0), using as-is.",
-                    local_temperature
-                );
+
+        let mut local_temperature = settings.temperature;
+        let mut temperature_source = settings.sources.temperature.clone();
+        if let Some((value, source)) = take_setting_directive(&mut messages, &re_p, "p") {
+            if let Ok(temp) = value.parse::<f32>() {
+                local_temperature = temp;
+                temperature_source = source;
+                // Optional: Clamp to reasonable range (e.g., 0.0 to 2.0)
+                if !(0.0..=2.0).contains(&local_temperature) {
+                    println!(
+                        "Warning: Specified temperature {} is outside typical range (0.0-2.0), using as-is.",
+                        local_temperature
+                    );
+                }
             }
-            println!("Setting `temperature` API parameter to {}", local_temperature);
         }
+        print!("{}", settings.report(current_level, &level_source, local_temperature, &temperature_source));
 
-        // Expand other placeholders ONLY in user messages (prompts to the API)
-        for msg in messages.iter_mut() {
+        // Build the client, for any @url pages and the API
+        let client = Client::builder()
+            .timeout(Duration::from_secs(settings.api_timeout))
+            .build()
+            .map_err(io::Error::other)?;
+
+        // Find the `@c:` includes written in the prompts before expanding placeholders,
+        // holding the included turns back so nothing in them is expanded
+        let root = env::current_dir()?;
+        let (mut messages, held) = include::include_conversations(messages, chat_path, &root, &settings.markers)?;
+        let last = messages.len() - 1;
+
+        // Expand placeholders ONLY in user messages (prompts to the API)
+        let refresh = snapshot::take_refresh(&mut messages);
+        let mut expander = expand::Expander::new(settings);
+        let mut snapshots = snapshot::Snapshots::open(chat_path, settings.snapshot_includes)?;
+        if settings.snapshot_includes && refresh {
+            println!("@refresh: reading every included file afresh");
+            snapshots.clear();
+        }
+        expander.use_snapshots(snapshots);
+        expander
+            .fetch_pages(&client, messages.iter().filter(|m| m.role == "user").map(|m| m.content.as_str()))
+            .await;
+        for (i, msg) in messages.iter_mut().enumerate() {
             if msg.role == "user" {
-                msg.content = expand_placeholders(&msg.content)?;
+                msg.content = expander.expand_placeholders(&msg.content, i == last)?;
             }
         }
+        let mut snapshots = expander.finish();
+        let messages = held.restore(messages);
 
         // Log the expanded messages (DEBUG level)
         log::debug!("Expanded messages for API request: {:?}", messages);
 
-        // Prepend system instructions ONLY if flag is enabled
+        // Prepend the chat's system prompt, and the file request instructions ONLY if flag is enabled
         let mut api_messages = messages.clone();  // Clone to avoid mutating original
-        if auto_request_files {
+        let mut system_content = settings.system_prompt.clone().unwrap_or_default();
+        if settings.auto_request_files {
+            system_content.push_str(SYSTEM_INSTRUCTIONS);
+        }
+        if !system_content.trim().is_empty() {
             api_messages.insert(0, Message {
                 role: "system".to_string(),
-                content: SYSTEM_INSTRUCTIONS.to_string(),
+                content: system_content.trim().to_string(),
             });
         }
 
-        // Get API key, build client
+        // Get API key
         let api_key = env::var("XAI_API_KEY").map_err(|_| io::Error::new(io::ErrorKind::NotFound, "XAI_API_KEY not set"))?;
-        let client = Client::builder()
-            .timeout(Duration::from_secs(api_timeout))
-            .build()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
         // Inner loop for handling truncation retries (in-memory, no file re-read)
         let mut needs_reprocess = false;
+        let mut retries = 0;
         loop {
             // Create request with current max_tokens
             let req = ChatRequest {
-                model: model.to_string(),
+                model: settings.model.clone(),
                 messages: api_messages.clone(),  // Clone to keep immutable
                 temperature: local_temperature,
                 max_tokens: parse_level(current_level),
@@ -461,7 +1385,7 @@ async fn process_chat_file(
 
             // Build the request
             let request_builder = client
-                .post("https://api.x.ai/v1/chat/completions")
+                .post(API_URL)
                 .header("Content-Type", "application/json")
                 .header("Authorization", format!("Bearer {}", api_key))
                 .json(&req);
@@ -470,17 +1394,36 @@ async fn process_chat_file(
             println!("Grok is thinking... (max_tokens: {}, temperature: {})", req.max_tokens, local_temperature);
 
             // Send and await
+            let sent_at = Utc::now();
+            let started = Instant::now();
             let res = request_builder.send().await;
 
             match res {
                 Ok(resp) if resp.status().is_success() => {
                     let chat_resp: ChatResponse = resp.json().await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    // The prompts have been sent, so what they were sent with can be frozen
+                    if let Some(mut snapshots) = snapshots.take() {
+                        if let Err(e) = snapshots.save() {
+                            println!("Warning: Failed to save snapshots: {}", e);
+                        }
+                    }
                     let assistant_content = chat_resp.choices[0].message.content.clone();
                     let finish_reason = chat_resp.choices[0].finish_reason.clone();
-
-                    // Check if this is a file request (only if flag is enabled)
+                    let meta = ResponseMeta {
+                        time: sent_at,
+                        model: req.model.clone(),
+                        temperature: req.temperature,
+                        max_tokens: req.max_tokens,
+                        usage: chat_resp.usage.clone(),
+                        latency: started.elapsed(),
+                        finish_reason: finish_reason.clone(),
+                        retries,
+                    };
+
+                    // Check if this is a file request (only if flag is enabled, and not when
+                    // regenerating, as the requested files would land after the history resent)
                     let mut is_file_request = false;
-                    if auto_request_files {
+                    if settings.auto_request_files && regen.is_none() {
                         let trimmed = assistant_content.trim();
                         if trimmed.starts_with("GROK REQUESTS FILES:") {
                             let rest = trimmed.strip_prefix("GROK REQUESTS FILES:").unwrap().trim();
@@ -489,7 +1432,7 @@ async fn process_chat_file(
                                 let paths: Vec<String> = rest.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
 
                                 // Validate paths
-                                let cwd = env::current_dir().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                                let cwd = env::current_dir().map_err(io::Error::other)?;
                                 let mut all_valid = true;
                                 let mut valid_paths = vec![];
                                 for p in paths.iter() {
@@ -517,7 +1460,7 @@ async fn process_chat_file(
                                 if all_valid && !valid_paths.is_empty() {
                                     // Append visible note and placeholders to the END of the file (augments the last USER PROMPT)
                                     let mut file = fs::OpenOptions::new().append(true).open(chat_path)?;
-                                    writeln!(file, "\n\nGROK REQUESTED FILES:")?;
+                                    writeln!(file, "\n\n{}", REQUESTED_FILES_MARKER)?;
                                     for vp in valid_paths {
                                         writeln!(file, "@f:{}", vp)?;  // No space after 'f'
                                     }
@@ -537,8 +1480,9 @@ async fn process_chat_file(
 
                     // Check for truncation
                     let is_truncated = finish_reason.as_ref().map(|r| r == "max_tokens" || r == "length").unwrap_or(false);
-                    if auto_increase_max_tokens && is_truncated && current_level < MAX_LEVEL {
+                    if settings.auto_increase_max_tokens && is_truncated && current_level < MAX_LEVEL {
                         current_level += 1;
+                        retries += 1;
                         println!(
                             "Response truncated. Retrying with higher max_tokens: L{} ({} tokens)",
                             current_level, parse_level(current_level)
@@ -549,20 +1493,45 @@ async fn process_chat_file(
 
                     // Otherwise, treat as final response
                     println!("Grok has thought.");
-                    let mut file = fs::OpenOptions::new().append(true).open(chat_path)?;
-                    writeln!(
-                        file,
-                        "\n{}:\n{}\n\n{}:\n",
-                        GROK_RESPONSE_MARKER,
-                        assistant_content,
-                        USER_PROMPT_MARKER
-                    )?;
+                    let assistant_content = format!(
+                        "{}\n{}",
+                        meta.to_comment(),
+                        escape_markers(&close_open_fence(&assistant_content), &settings.markers)
+                    );
+
+                    // A regenerated response replaces the last one in place, as long as the
+                    // history before it hasn't been edited while Grok was thinking
+                    let current = fs::read_to_string(chat_path)?;
+                    match &regen {
+                        Some(regen) if current.starts_with(history) => {
+                            let regenerated = regen::replace_response(history, &assistant_content, regen, &settings.markers);
+                            write_atomically(chat_path, &regenerated)?;
+                        }
+                        _ => {
+                            if regen.is_some() {
+                                println!("Warning: Chat file changed while regenerating; appending the new response instead.");
+                            }
+                            let mut file = fs::OpenOptions::new().append(true).open(chat_path)?;
+                            writeln!(
+                                file,
+                                "\n{}\n{}\n\n{}\n",
+                                settings.markers.grok,
+                                assistant_content,
+                                settings.markers.user
+                            )?;
+                        }
+                    }
 
                     // If still truncated at max level, print warning
                     if is_truncated {
                         println!("Warning: Response truncated even at max level L{} ({} tokens)!", MAX_LEVEL, parse_level(MAX_LEVEL));
                     }
 
+                    // Move older turns out of the way if the chat has grown too long
+                    if let Err(e) = archive::archive_if_needed(chat_path, settings, &client, &api_key).await {
+                        println!("Warning: Failed to archive older turns: {}", e);
+                    }
+
                     // Play chime sound
                     play_chime().await;
 
@@ -574,12 +1543,12 @@ async fn process_chat_file(
                     let err_body = resp.text().await.unwrap_or_default();
                     println!("Grok failed to respond.");
                     play_warning().await;
-                    return Err(io::Error::new(io::ErrorKind::Other, format!("API error: {} - Body: {}", status, err_body)));
+                    return Err(io::Error::other(format!("API error: {} - Body: {}", status, err_body)));
                 }
                 Err(e) => {
                     println!("Grok failed to respond.");
                     play_warning().await;
-                    return Err(io::Error::new(io::ErrorKind::Other, format!("Request error: {:?}", e)));
+                    return Err(io::Error::other(format!("Request error: {:?}", e)));
                 },
             }
         }  // End inner loop
@@ -594,161 +1563,263 @@ async fn process_chat_file(
     Ok(())
 }
 
-fn parse_chat_messages(content: &str) -> Vec<Message> {
-    let mut messages = Vec::new();
+// Split a leading front-matter block from a chat file (or a persona's file, which
+// has no `markers`), returning the block's delimiter, its raw contents and the rest
+// of the file. In a chat, `---` lines holding a marker line between them are
+// Markdown rules around a turn, not front matter.
+fn split_front_matter<'a>(content: &'a str, markers: Option<&Markers>) -> Option<(&'static str, &'a str, &'a str)> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    let delimiter = ["+++", "---"].into_iter().find(|d| {
+        content.strip_prefix(d).is_some_and(|rest| rest.starts_with('\n') || rest.starts_with("\r\n"))
+    })?;
+
+    let body_start = content.find('\n')? + 1;
+    let mut offset = body_start;
+    for line in content[body_start..].split_inclusive('\n') {
+        if line.trim_end() == delimiter {
+            return Some((delimiter, &content[body_start..offset], &content[offset + line.len()..]));
+        }
+        if markers.is_some_and(|markers| markers.role_of(line).is_some()) {
+            return None;
+        }
+        offset += line.len();
+    }
+    None
+}
+
+fn parse_front_matter(content: &str, markers: Option<&Markers>) -> io::Result<Option<FrontMatter>> {
+    let Some((delimiter, raw, _)) = split_front_matter(content, markers) else {
+        return Ok(None);
+    };
+    let parsed = if delimiter == "+++" {
+        toml::from_str(raw).map_err(|e| e.to_string())
+    } else if raw.trim().is_empty() {
+        Ok(FrontMatter::default())
+    } else {
+        serde_norway::from_str(raw).map_err(|e| e.to_string())
+    };
+    parsed
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid front matter: {}", e)))
+}
+
+fn parse_chat_messages(content: &str, markers: &Markers) -> Vec<Message> {
+    let mut sections: Vec<(String, String)> = Vec::new();
     let mut current_role: Option<String> = None;
     let mut current_content = String::new();
 
-    for line in content.lines() {
-        if line == "USER PROMPT:" || line == "GROK RESPONSE:" {
+    // The front matter holds settings, not conversation
+    let content = match split_front_matter(content, Some(markers)) {
+        Some((_, _, body)) => body,
+        None => content.strip_prefix('\u{feff}').unwrap_or(content),
+    };
+
+    for (line, marker_role, in_code) in scan_chat_lines(content, markers) {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if let Some(role) = marker_role {
             // Add previous section if content is non-empty
-            let trimmed = current_content.trim().to_string();
-            if !trimmed.is_empty() {
+            if !current_content.trim().is_empty() {
                 let role = current_role.take().unwrap_or("user".to_string());
-                messages.push(Message {
-                    role,
-                    content: trimmed,
-                });
+                sections.push((role, current_content.clone()));
             }
 
             // Start new section
-            current_role = Some(if line == "USER PROMPT:" { "user".to_string() } else { "assistant".to_string() });
+            current_role = Some(role.to_string());
             current_content.clear();
         } else {
-            // Append to current content
+            // Append to current content, restoring any escaped marker lines
+            let line = if in_code { Cow::Borrowed(line) } else { unescape_marker_line(line, markers) };
             writeln!(&mut current_content, "{}", line).expect("Failed to write to String");
         }
     }
 
     // Add the last section if content is non-empty
-    let trimmed = current_content.trim().to_string();
-    if !trimmed.is_empty() {
+    if !current_content.trim().is_empty() {
         let role = current_role.unwrap_or("user".to_string());
-        messages.push(Message {
-            role,
-            content: trimmed,
-        });
+        sections.push((role, current_content));
+    }
+
+    // Drop comments, skipped turns and folded alternative responses, which stay in
+    // the file but are never sent. Skipping a user prompt also skips the response to it.
+    let mut messages = Vec::new();
+    let mut skip_reply = false;
+    for (role, raw) in sections {
+        let raw = if role == "assistant" { regen::strip_alternatives(&raw) } else { raw };
+        let content = strip_comments(&raw).trim().to_string();
+        if content.is_empty() {
+            continue;
+        }
+        if role == "assistant" && skip_reply {
+            skip_reply = false;
+            continue;
+        }
+        // Only a prompt can be skipped; a response that mentions `@skip` is just text
+        if role == "user" && has_directive(&content, "@skip") {
+            skip_reply = true;
+            continue;
+        }
+        skip_reply = false;
+        messages.push(Message { role, content });
     }
 
     messages
 }
 
-fn expand_placeholders(text: &str) -> io::Result<String> {
-    let re = Regex::new(r"@f\s*:(\S+)|@d\s*:(\S+)").unwrap();
-    let mut result = String::new();
-    let mut last_end = 0;
-
-    for cap in re.captures_iter(text) {
-        let match_range = cap.get(0).unwrap();
-        let placeholder = match_range.as_str();
-        let match_start = match_range.start();
-        result.push_str(&text[last_end..match_start]);
+// Remove HTML comments (`<!-- ... -->`, possibly spanning lines) from a section,
+// leaving code blocks and inline code spans untouched. Lines left blank by a
+// removed comment are dropped entirely.
+fn strip_comments(text: &str) -> String {
+    let mut fences = FenceTracker::default();
+    let mut in_comment = false;
+    let mut stripped = String::with_capacity(text.len());
+
+    for line in text.lines() {
+        if !in_comment && fences.is_code(line) {
+            stripped.push_str(line);
+            stripped.push('\n');
+            continue;
+        }
 
-        if let Some(file_path) = cap.get(1) {
-            let path_str = file_path.as_str();
-            match expand_file_path(path_str) {
-                Ok(expanded) => result.push_str(&expanded),
-                Err(e) => {
-                    println!("Warning: Failed to expand file placeholder '{}' : {} (path: {})", placeholder, e, path_str);
-                    result.push_str(placeholder);
-                }
-            }
-        } else if let Some(dir_path) = cap.get(2) {
-            let path_str = dir_path.as_str();
-            match expand_dir_tree(path_str) {
-                Ok(expanded) => result.push_str(&expanded),
-                Err(e) => {
-                    println!("Warning: Failed to expand directory placeholder '{}' : {} (path: {})", placeholder, e, path_str);
-                    result.push_str(placeholder);
+        let mut kept = String::new();
+        let mut rest = line;
+        while !rest.is_empty() {
+            if in_comment {
+                match rest.find("-->") {
+                    Some(end) => {
+                        rest = &rest[end + 3..];
+                        in_comment = false;
+                    }
+                    None => rest = "",
                 }
+            } else if rest.starts_with("<!--") {
+                in_comment = true;
+                rest = &rest[4..];
+            } else if rest.starts_with('`') {
+                // Copy an inline code span verbatim (or the lone backticks, if unclosed)
+                let ticks = rest.len() - rest.trim_start_matches('`').len();
+                let span_end = rest[ticks..].find(&rest[..ticks]).map_or(ticks, |end| ticks + end + ticks);
+                kept.push_str(&rest[..span_end]);
+                rest = &rest[span_end..];
+            } else {
+                let next = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| *c == '<' || *c == '`')
+                    .map_or(rest.len(), |(i, _)| i);
+                kept.push_str(&rest[..next]);
+                rest = &rest[next..];
             }
         }
 
-        last_end = match_range.end();
+        if kept.trim().is_empty() && kept.len() != line.len() {
+            continue;
+        }
+        stripped.push_str(&kept);
+        stripped.push('\n');
     }
 
-    result.push_str(&text[last_end..]);
-    Ok(result)
+    stripped
 }
 
-fn expand_file_path(path_str: &str) -> io::Result<String> {
-    let path = Path::new(path_str);
-    let mut output = String::new();
-
-    if path_str.contains('*') || path_str.contains('?') {
-        // Glob
-        let mut files: Vec<_> = glob(path_str)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
-            .filter_map(|res| res.ok().filter(|p| p.is_file()))
-            .collect();
-        if files.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "No files matched the pattern"));
-        }
-        files.sort();
-        for p in files {
-            let content = fs::read_to_string(&p)?;
-            writeln!(&mut output, "Contents of {}:\n```\n{}\n```\n", p.display(), content).expect("Failed to write to String");
-        }
-    } else if path.is_dir() {
-        // Directory recurse
-        if !path.exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
-        }
-        let mut entries: Vec<_> = WalkDir::new(path).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).collect();
-        if entries.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "No files found in directory"));
-        }
-        entries.sort_by_key(|e| e.path().to_owned());
-        for entry in entries {
-            let entry_path = entry.path();
-            if !entry_path.exists() {
-                return Err(io::Error::new(io::ErrorKind::NotFound, format!("File not found in directory: {}", entry_path.display())));
-            }
-            let content = fs::read_to_string(entry_path)?;
-            writeln!(&mut output, "Contents of {}:\n```\n{}\n```\n", entry_path.display(), content).expect("Failed to write to String");
-        }
-    } else {
-        // Single file
-        if !path.exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
-        }
-        if !path.is_file() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is not a file"));
-        }
-        let content = fs::read_to_string(path)?;
-        writeln!(&mut output, "Contents of {}:\n```\n{}\n```\n", path.display(), content).expect("Failed to write to String");
-    }
+// Whether a section holds a directive such as `@skip` on a line of its own (outside any code block)
+fn has_directive(text: &str, directive: &str) -> bool {
+    let lines: Vec<&str> = text.lines().collect();
+    lines
+        .iter()
+        .zip(code_lines(&lines, |_| false))
+        .any(|(line, in_code)| !in_code && line.trim() == directive)
+}
 
-    Ok(output)
+// A path as shown to the user: relative to the current directory when it's under it
+fn display_path(path: &Path) -> String {
+    env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(|p| p.display().to_string()))
+        .unwrap_or_else(|| path.display().to_string())
 }
 
-fn expand_dir_tree(path_str: &str) -> io::Result<String> {
-    let path = Path::new(path_str);
-    if !path.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+// Directories holding a library of Markdown files (personas, templates), global
+// first, so a project's `.gchat/<kind>` shadows the user's `~/.config/gchat/<kind>`
+fn library_dirs(kind: &str) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(config_dir) = dirs::config_dir() {
+        dirs.push(config_dir.join("gchat").join(kind));
     }
-    if !path.is_dir() {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is not a directory"));
+    dirs.push(Path::new(".gchat").join(kind));
+    dirs
+}
+
+// The file for a library entry, if a valid name has one
+fn library_file(kind: &str, name: &str) -> Option<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return None;
     }
+    library_dirs(kind)
+        .into_iter()
+        .rev()
+        .map(|dir| dir.join(format!("{}.md", name)))
+        .find(|path| path.is_file())
+}
 
-    let mut output = format!("Contents of directory {}:\n```\n", path.display());
-    let mut entries: Vec<_> = WalkDir::new(path).min_depth(1).into_iter().filter_map(|e| e.ok()).collect();
-    if entries.is_empty() {
-        output.push_str("(empty directory)\n");
-    } else {
-        entries.sort_by_key(|e| e.path().to_owned());
-        for entry in entries {
-            let rel_path = entry.path().strip_prefix(path).unwrap();
-            let indent = "  ".repeat(entry.depth() - 1);
-            if entry.file_type().is_dir() {
-                writeln!(&mut output, "{}{}/", indent, rel_path.display()).expect("Failed to write to String");
-            } else {
-                writeln!(&mut output, "{}{}", indent, rel_path.display()).expect("Failed to write to String");
+// Every entry in a library, by name, sorted
+fn library_entries(kind: &str) -> BTreeMap<String, PathBuf> {
+    let mut entries = BTreeMap::new();
+    for dir in library_dirs(kind) {
+        let Ok(files) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in files.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().and_then(|e| e.to_str()) != Some("md") || !path.is_file() {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                entries.insert(name.to_string(), path.clone());
             }
         }
     }
-    output.push_str("```\n");
-    Ok(output)
+    entries
+}
+
+// Replace a file's contents via a temporary file and a rename, so readers (and a
+// crash part-way through) never see it half-written
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+// Rewrite every marker line of a chat file from one marker style to another. The
+// text between marker lines is unescaped for the old markers and escaped again for
+// the new ones, so a line that only now looks like a marker doesn't become a turn
+// boundary. Returns the number of marker lines converted.
+fn convert_markers(chat_path: &Path, from: &Markers, to: &Markers) -> io::Result<usize> {
+    let content = fs::read_to_string(chat_path)?;
+    let mut converted = String::with_capacity(content.len());
+    let mut count = 0;
+
+    // Copy any front matter through untouched
+    let body = split_front_matter(&content, Some(from)).map(|(_, _, body)| body).unwrap_or(&content);
+    converted.push_str(&content[..content.len() - body.len()]);
+
+    let mut text = String::new();
+    for (line, role, in_code) in scan_chat_lines(body, from) {
+        let Some(role) = role else {
+            text.push_str(&if in_code { Cow::Borrowed(line) } else { unescape_marker_line(line, from) });
+            continue;
+        };
+        converted.push_str(&escape_markers(&text, to));
+        text.clear();
+        let ending = &line[line.trim_end_matches(['\n', '\r']).len()..];
+        let bom = if line.starts_with('\u{feff}') { "\u{feff}" } else { "" };
+        let marker = if role == "user" { &to.user } else { &to.grok };
+        write!(converted, "{}{}{}", bom, marker, ending).expect("Failed to write to String");
+        count += 1;
+    }
+    converted.push_str(&escape_markers(&text, to));
+
+    write_atomically(chat_path, &converted)?;
+    Ok(count)
 }
 
 // Play a pleasant chime sound from bundled MP3
@@ -788,3 +1859,298 @@ async fn play_warning() {
     .await
     .expect("Failed to play warning");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain() -> Markers {
+        Markers::from_style("plain").unwrap()
+    }
+
+    #[test]
+    fn configured_markers_must_differ() {
+        let markers = |user: &str, grok: &str| Markers { user: user.to_string(), grok: grok.to_string() };
+        assert!(plain().validate().is_ok());
+        assert!(markers("Q:", "A:").validate().is_ok());
+        assert!(markers("", "A:").validate().is_err());
+        assert!(markers("Q:", "  ").validate().is_err());
+        assert!(markers("Q:", "Q:").validate().is_err());
+        assert!(markers("Q:", "Q: ").validate().is_err());
+    }
+
+    fn roles(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[test]
+    fn fence_tracker_matches_fence_kind_and_length() {
+        let mut fences = FenceTracker::default();
+        let lines = ["````md", "```", "~~~", "````", "after"];
+        let in_code: Vec<bool> = lines.iter().map(|line| fences.is_code(line)).collect();
+        assert_eq!(in_code, [true, true, true, true, false]);
+
+        // A closing fence has no info string, and backtick info strings have no backticks
+        let mut fences = FenceTracker::default();
+        assert!(fences.is_code("~~~ rust"));
+        assert!(fences.is_code("~~~ rust"));
+        assert!(fences.is_code("~~~"));
+        assert!(!fences.is_code("``` a`b"));
+        assert!(!fences.is_code("    ```"));
+    }
+
+    #[test]
+    fn markers_in_code_blocks_are_not_boundaries() {
+        let content = "USER PROMPT:\nHere is a transcript:\n```\nGROK RESPONSE:\nhi\n```\n~~~\nUSER PROMPT:\n~~~\n    GROK RESPONSE:\n\nGROK RESPONSE:\nSeen.\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(
+            messages[0].content,
+            "Here is a transcript:\n```\nGROK RESPONSE:\nhi\n```\n~~~\nUSER PROMPT:\n~~~\n    GROK RESPONSE:"
+        );
+        assert_eq!(messages[1].content, "Seen.");
+    }
+
+    #[test]
+    fn crlf_bom_and_trailing_whitespace_on_markers() {
+        let content = "\u{feff}USER PROMPT:  \r\nHello\r\n\r\n  GROK RESPONSE:\t\r\nHi\r\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[0].content, "Hello");
+        assert_eq!(messages[1].content, "Hi");
+
+        let sections = chat_sections(content, &plain());
+        assert_eq!(sections.len(), 2);
+        assert_eq!(&content[sections[1].1.clone()], "  GROK RESPONSE:\t\r\nHi\r\n");
+    }
+
+    #[test]
+    fn heading_markers() {
+        let content = "## 🧑 User\nHow?\n\n## 🤖 Grok\nLike so.\n\n## 🧑 User\n";
+        let messages = parse_chat_messages(content, &Markers::from_style("heading").unwrap());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[1].content, "Like so.");
+        assert!(parse_chat_messages(content, &plain()).iter().all(|m| m.role == "user"));
+    }
+
+    #[test]
+    fn close_open_fence_closes_with_the_same_fence() {
+        assert_eq!(close_open_fence("text\n````rust\nfn f() {}"), "text\n````rust\nfn f() {}\n````");
+        assert_eq!(close_open_fence("```\ncode\n```"), "```\ncode\n```");
+    }
+
+    #[test]
+    fn unclosed_fence_in_prompt_does_not_hide_markers() {
+        let content = "USER PROMPT:\n```rust\nfn main() {}\n\nGROK RESPONSE:\nIt does not.\n\nUSER PROMPT:\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[0].content, "```rust\nfn main() {}");
+        assert_eq!(messages[1].content, "It does not.");
+    }
+
+    #[test]
+    fn stray_fence_does_not_pair_with_a_fence_in_the_response() {
+        let content = "USER PROMPT:\n```\nstray\n\nGROK RESPONSE:\nTry:\n```\nlet x = 1;\n```\n\nUSER PROMPT:\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[1].content, "Try:\n```\nlet x = 1;\n```");
+    }
+
+    #[test]
+    fn stray_fence_after_a_closed_block_quoting_a_marker() {
+        let content = "USER PROMPT:\nQuote:\n```\nUSER PROMPT:\n```\n\nGROK RESPONSE:\nOk.\n\nUSER PROMPT:\n```\nunfinished\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant", "user"]);
+        assert_eq!(messages[0].content, "Quote:\n```\nUSER PROMPT:\n```");
+        assert_eq!(messages[2].content, "```\nunfinished");
+    }
+
+    #[test]
+    fn strip_comments_keeps_code() {
+        let text = "Keep <!-- drop --> this\n<!-- whole line -->\nstarts <!-- spans\nlines --> ends\n`<!-- inline code -->`\n```\n<!-- in a block -->\n```\n";
+        assert_eq!(
+            strip_comments(text),
+            "Keep  this\nstarts \n ends\n`<!-- inline code -->`\n```\n<!-- in a block -->\n```\n"
+        );
+        assert_eq!(strip_comments("a\n<!-- never closed\nb\n"), "a\n");
+    }
+
+    #[test]
+    fn comments_and_skipped_turns_are_not_sent() {
+        let content = "USER PROMPT:\n<!-- a note -->\nFirst\n\nGROK RESPONSE:\n<!-- gchat: time=x -->\nOne\n\nUSER PROMPT:\nAside\n@skip\n\nGROK RESPONSE:\nIgnored\n\nUSER PROMPT:\n`@skip` is quoted, and so is\n```\n@skip\n```\nand this isn't a directive: @skip\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant", "user"]);
+        assert_eq!(messages[0].content, "First");
+        assert_eq!(messages[1].content, "One");
+        assert!(messages[2].content.starts_with("`@skip` is quoted"));
+    }
+
+    #[test]
+    fn responses_mentioning_skip_are_kept() {
+        let content = "USER PROMPT:\nHow do I leave a turn out?\n\nGROK RESPONSE:\nPut this on its own line:\n\n@skip\n\nUSER PROMPT:\nThanks\n";
+        let messages = parse_chat_messages(content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant", "user"]);
+        assert_eq!(messages[1].content, "Put this on its own line:\n\n@skip");
+    }
+
+    // Prompts and responses in turn, starting with a prompt
+    fn turns(contents: &[&str]) -> Vec<Message> {
+        contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| Message {
+                role: if i % 2 == 0 { "user" } else { "assistant" }.to_string(),
+                content: content.to_string(),
+            })
+            .collect()
+    }
+
+    fn temperature_directive(contents: &[&str]) -> Option<(String, String)> {
+        let re = Regex::new(r"@p(!?)\s*:\s*(\d*\.?\d+)").unwrap();
+        take_setting_directive(&mut turns(contents), &re, "p")
+    }
+
+    #[test]
+    fn setting_directives() {
+        let setting = |value: &str, source: &str| Some((value.to_string(), source.to_string()));
+        // One-shot in the prompt being sent
+        assert_eq!(temperature_directive(&["a", "b", "@p:0.2 c"]), setting("0.2", "@p: in this prompt"));
+        // One-shot in an earlier prompt: ignored
+        assert_eq!(temperature_directive(&["@p:0.2 a", "b", "c"]), None);
+        // Sticky persists until another sticky replaces it
+        assert_eq!(temperature_directive(&["@p!:0.2 a", "b", "c"]), setting("0.2", "sticky @p!: in turn 1"));
+        assert_eq!(
+            temperature_directive(&["@p!:0.2 a", "b", "@p!:0.7 c", "d", "@p:0.1 e @p!:0.9"]),
+            setting("0.1", "@p: in this prompt")
+        );
+        assert_eq!(
+            temperature_directive(&["@p!:0.2 a", "b", "@p!:0.7 c", "d", "e"]),
+            setting("0.7", "sticky @p!: in turn 3")
+        );
+        // One-shot overrides sticky, in the same prompt or an earlier one
+        assert_eq!(temperature_directive(&["@p!:0.2 a", "b", "@p:0.5 c"]), setting("0.5", "@p: in this prompt"));
+        assert_eq!(temperature_directive(&["a", "b", "@p!:0.2 @p:0.5 c"]), setting("0.5", "@p: in this prompt"));
+        // Only prompts count
+        assert_eq!(temperature_directive(&["a", "@p!:0.2", "c"]), None);
+    }
+
+    #[test]
+    fn setting_directives_are_removed_from_prompts() {
+        let re = Regex::new(r"@t(!?)\s*:\s*L(\d+)").unwrap();
+        let mut messages = turns(&["@t!:L5 a", "use @t:L2", "b @t:L4"]);
+        assert_eq!(take_setting_directive(&mut messages, &re, "t"), Some(("4".to_string(), "@t: in this prompt".to_string())));
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, [" a", "use @t:L2", "b "]);
+    }
+
+    #[test]
+    fn front_matter_blocks() {
+        let toml = "+++\r\nmodel = \"grok-3\"\r\ntemperature = 0.2\r\n+++\r\nUSER PROMPT:\r\nHi\r\n";
+        let (delimiter, raw, body) = split_front_matter(toml, Some(&plain())).unwrap();
+        assert_eq!((delimiter, raw, body), ("+++", "model = \"grok-3\"\r\ntemperature = 0.2\r\n", "USER PROMPT:\r\nHi\r\n"));
+        let front_matter = parse_front_matter(toml, Some(&plain())).unwrap().unwrap();
+        assert_eq!((front_matter.model.as_deref(), front_matter.temperature), (Some("grok-3"), Some(0.2)));
+
+        let yaml = "\u{feff}---\nmax_tokens: L5\ncontext:\n  - \"@f:./src\"\n---\nUSER PROMPT:\nHi\n";
+        let front_matter = parse_front_matter(yaml, Some(&plain())).unwrap().unwrap();
+        assert_eq!(front_matter.max_tokens.as_deref(), Some("L5"));
+        assert_eq!(front_matter.context, ["@f:./src"]);
+        let messages = parse_chat_messages(yaml, &plain());
+        assert_eq!(roles(&messages), ["user"]);
+        assert_eq!(messages[0].content, "Hi");
+
+        // Not front matter: unclosed, or not on the first line
+        assert!(split_front_matter("---\nmodel: x\nUSER PROMPT:\n", Some(&plain())).is_none());
+        assert!(split_front_matter("\n---\nmodel: x\n---\n", Some(&plain())).is_none());
+        assert!(parse_front_matter("---\n---\nUSER PROMPT:\n", Some(&plain())).unwrap().is_some());
+    }
+
+    #[test]
+    fn markdown_rules_are_not_front_matter() {
+        let content = "---\nUSER PROMPT:\nA question between rules\n---\nGROK RESPONSE:\nAn answer\n";
+        assert!(split_front_matter(content, Some(&plain())).is_none());
+        assert!(parse_front_matter(content, Some(&plain())).unwrap().is_none());
+        let messages = parse_chat_messages(content, &plain());
+        // The leading rule is text before the first marker, sent like any such text
+        assert_eq!(roles(&messages), ["user", "user", "assistant"]);
+        assert_eq!(messages[1].content, "A question between rules\n---");
+        // Without markers (a persona's file) only the delimiters count
+        assert!(split_front_matter(content, None).is_some());
+    }
+
+    #[test]
+    fn unknown_front_matter_keys_are_errors() {
+        let error = parse_front_matter("---\ntemprature: 0.2\n---\n", Some(&plain())).unwrap_err();
+        assert!(error.to_string().contains("temprature"), "{}", error);
+        let error = parse_front_matter("+++\ntemprature = 0.2\n+++\n", Some(&plain())).unwrap_err();
+        assert!(error.to_string().contains("temprature"), "{}", error);
+    }
+
+    // A response as written to the chat file, followed by the next prompt's marker
+    fn written(response: &str, markers: &Markers) -> String {
+        format!(
+            "{}\nQ\n\n{}\n{}\n\n{}\n",
+            markers.user,
+            markers.grok,
+            escape_markers(&close_open_fence(response), markers),
+            markers.user
+        )
+    }
+
+    #[test]
+    fn escaped_markers_round_trip() {
+        let response = "Lines:\nUSER PROMPT:\n  GROK RESPONSE:\n\\USER PROMPT:\n\\\\GROK RESPONSE:\n    USER PROMPT:\nUSER PROMPT: not a marker\n```\nUSER PROMPT:\n```";
+        let content = written(response, &plain());
+        assert!(content.contains("\n\\USER PROMPT:\n  \\GROK RESPONSE:\n\\\\USER PROMPT:\n\\\\\\GROK RESPONSE:\n"));
+        assert!(content.contains("```\nUSER PROMPT:\n```"));
+
+        let messages = parse_chat_messages(&content, &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[1].content, response);
+    }
+
+    #[test]
+    fn escaped_markers_round_trip_with_crlf_and_headings() {
+        let heading = Markers::from_style("heading").unwrap();
+        let response = "Heading markers:\r\n## 🧑 User\r\n## 🤖 Grok  \r\nUSER PROMPT:\r\n";
+        let messages = parse_chat_messages(&written(response, &heading), &heading);
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[1].content, "Heading markers:\n## 🧑 User\n## 🤖 Grok  \nUSER PROMPT:");
+    }
+
+    #[test]
+    fn truncated_response_fence_is_closed_before_the_next_marker() {
+        let response = "Here:\n```rust\nfn main() {\nUSER PROMPT:";
+        let messages = parse_chat_messages(&written(response, &plain()), &plain());
+        assert_eq!(roles(&messages), ["user", "assistant"]);
+        assert_eq!(messages[1].content, format!("{}\n```", response));
+    }
+
+    #[test]
+    fn unescape_only_touches_escaped_markers() {
+        let markers = plain();
+        assert_eq!(unescape_marker_line("\\USER PROMPT:", &markers), "USER PROMPT:");
+        assert_eq!(unescape_marker_line("  \\\\GROK RESPONSE:", &markers), "  \\GROK RESPONSE:");
+        assert_eq!(unescape_marker_line("\\USER PROMPT: and more", &markers), "\\USER PROMPT: and more");
+        assert_eq!(unescape_marker_line("\\n", &markers), "\\n");
+    }
+
+    #[test]
+    fn convert_keeps_turns_and_escapes() {
+        let content = "USER PROMPT:\nWhat do markers look like?\n\nGROK RESPONSE:\nLike this:\n## 🤖 Grok\n\\USER PROMPT:\n\nUSER PROMPT:\nThanks\n";
+        let path = env::temp_dir().join(format!("gchat-convert-{}.md", std::process::id()));
+        fs::write(&path, content).unwrap();
+        let heading = Markers::from_style("heading").unwrap();
+
+        assert_eq!(convert_markers(&path, &plain(), &heading).unwrap(), 3);
+        let converted = fs::read_to_string(&path).unwrap();
+        assert!(converted.contains("\n\\## 🤖 Grok\nUSER PROMPT:\n"));
+        let before: Vec<(String, String)> = parse_chat_messages(content, &plain()).into_iter().map(|m| (m.role, m.content)).collect();
+        let after: Vec<(String, String)> = parse_chat_messages(&converted, &heading).into_iter().map(|m| (m.role, m.content)).collect();
+        assert_eq!(before, after);
+
+        convert_markers(&path, &heading, &plain()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+// Outlines of Rust source for `@outline:path`: the module tree, public items with
+// their doc comments, function signatures, struct and enum definitions and trait
+// declarations. Function bodies, private items and private fields are left out,
+// so a whole crate's interface costs a fraction of its source.
+
+use std::io;
+
+use quote::quote;
+use syn::{parse_quote, Attribute, Fields, ImplItem, Item, TraitItem, Visibility};
+
+// Attributes worth keeping in an outline; the rest are implementation detail
+const KEPT_ATTRIBUTES: [&str; 6] = ["doc", "derive", "cfg", "repr", "non_exhaustive", "must_use"];
+
+// The outline of a file, empty if it has nothing public
+pub fn outline(source: &str) -> io::Result<String> {
+    let mut file = syn::parse_file(source)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse as Rust: {}", e)))?;
+    file.attrs = kept_attributes(&file.attrs);
+    file.items = file.items.into_iter().filter_map(outline_item).collect();
+    if file.attrs.is_empty() && file.items.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(prettyplease::unparse(&file))
+}
+
+// An item as it appears in the outline, if it appears at all
+fn outline_item(item: Item) -> Option<Item> {
+    match item {
+        Item::Fn(f) if is_public(&f.vis) => {
+            let (attrs, vis, sig) = (kept_attributes(&f.attrs), f.vis, f.sig);
+            Some(Item::Verbatim(quote! { #(#attrs)* #vis #sig; }))
+        }
+        Item::Struct(mut s) if is_public(&s.vis) => {
+            s.attrs = kept_attributes(&s.attrs);
+            if let Fields::Named(fields) = &mut s.fields {
+                let count = fields.named.len();
+                fields.named = std::mem::take(&mut fields.named).into_iter().filter(|f| is_public(&f.vis)).collect();
+                for field in fields.named.iter_mut() {
+                    field.attrs = kept_attributes(&field.attrs);
+                }
+                // Like rustdoc, say so rather than show what looks like an empty struct
+                if fields.named.len() < count {
+                    s.attrs.push(parse_quote!(#[doc = " (Has private fields, not shown.)"]));
+                }
+            }
+            Some(Item::Struct(s))
+        }
+        Item::Enum(mut e) if is_public(&e.vis) => {
+            e.attrs = kept_attributes(&e.attrs);
+            for variant in e.variants.iter_mut() {
+                variant.attrs = kept_attributes(&variant.attrs);
+            }
+            Some(Item::Enum(e))
+        }
+        Item::Trait(mut t) if is_public(&t.vis) => {
+            t.attrs = kept_attributes(&t.attrs);
+            for trait_item in t.items.iter_mut() {
+                if let TraitItem::Fn(method) = trait_item {
+                    method.attrs = kept_attributes(&method.attrs);
+                    method.default = None;
+                    method.semi_token = Some(Default::default());
+                }
+            }
+            Some(Item::Trait(t))
+        }
+        Item::Impl(mut imp) => {
+            imp.attrs = kept_attributes(&imp.attrs);
+            if imp.trait_.is_some() {
+                // Which traits a type implements is part of its interface; how isn't
+                imp.items.clear();
+                return Some(Item::Impl(imp));
+            }
+            imp.items = std::mem::take(&mut imp.items)
+                .into_iter()
+                .filter_map(|impl_item| match impl_item {
+                    ImplItem::Fn(method) if is_public(&method.vis) => {
+                        let (attrs, vis, sig) = (kept_attributes(&method.attrs), method.vis, method.sig);
+                        Some(ImplItem::Verbatim(quote! { #(#attrs)* #vis #sig; }))
+                    }
+                    ImplItem::Const(c) if is_public(&c.vis) => Some(ImplItem::Const(c)),
+                    _ => None,
+                })
+                .collect();
+            (!imp.items.is_empty()).then_some(Item::Impl(imp))
+        }
+        // Every module, public or not, for the module tree, except tests
+        Item::Mod(m) if is_test(&m.attrs) => None,
+        Item::Mod(mut m) => {
+            m.attrs = kept_attributes(&m.attrs);
+            if let Some((_, items)) = &mut m.content {
+                *items = std::mem::take(items).into_iter().filter_map(outline_item).collect();
+            }
+            Some(Item::Mod(m))
+        }
+        Item::Const(mut c) if is_public(&c.vis) => {
+            c.attrs = kept_attributes(&c.attrs);
+            Some(Item::Const(c))
+        }
+        Item::Static(mut s) if is_public(&s.vis) => {
+            s.attrs = kept_attributes(&s.attrs);
+            Some(Item::Static(s))
+        }
+        Item::Type(mut t) if is_public(&t.vis) => {
+            t.attrs = kept_attributes(&t.attrs);
+            Some(Item::Type(t))
+        }
+        // Re-exports
+        Item::Use(u) if is_public(&u.vis) => Some(Item::Use(u)),
+        _ => None,
+    }
+}
+
+fn is_public(vis: &Visibility) -> bool {
+    !matches!(vis, Visibility::Inherited)
+}
+
+// Whether an item is `#[cfg(test)]`
+fn is_test(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("cfg") && attr.parse_args::<syn::Ident>().is_ok_and(|arg| arg == "test"))
+}
+
+fn kept_attributes(attrs: &[Attribute]) -> Vec<Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| KEPT_ATTRIBUTES.iter().any(|name| attr.path().is_ident(name)))
+        .cloned()
+        .collect()
+}
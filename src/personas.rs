@@ -0,0 +1,90 @@
+// Personas: reusable system prompts stored as Markdown files in
+// `~/.config/gchat/personas/` or a project's `.gchat/personas/`. A persona's own
+// front matter can set default settings such as the model and temperature.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::{library_dirs, library_entries, library_file, parse_front_matter, split_front_matter, FrontMatter};
+
+pub struct Persona {
+    pub name: String,
+    pub path: PathBuf,
+    pub front_matter: FrontMatter,
+    pub prompt: String,
+}
+
+// Load a persona by name; a project persona shadows a global one of the same name
+pub fn load_persona(name: &str) -> io::Result<Persona> {
+    let path = library_file("personas", name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Persona '{}' not found (run `gchat personas` to see what's available)", name),
+        )
+    })?;
+    read_persona(name, path)
+}
+
+// Every available persona, sorted by name
+pub fn list_personas() -> io::Result<Vec<Persona>> {
+    library_entries("personas")
+        .into_iter()
+        .map(|(name, path)| read_persona(&name, path))
+        .collect()
+}
+
+fn read_persona(name: &str, path: PathBuf) -> io::Result<Persona> {
+    let content = fs::read_to_string(&path)?;
+    let front_matter = parse_front_matter(&content, None)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?
+        .unwrap_or_default();
+    let prompt = match split_front_matter(&content, None) {
+        Some((_, _, body)) => body,
+        None => content.as_str(),
+    }
+    .trim()
+    .to_string();
+    Ok(Persona {
+        name: name.to_string(),
+        path,
+        front_matter,
+        prompt,
+    })
+}
+
+// A listing of the available personas for `gchat personas`
+pub fn describe_personas() -> io::Result<String> {
+    let personas = list_personas()?;
+    if personas.is_empty() {
+        let dirs: Vec<String> = library_dirs("personas").iter().map(|d| d.display().to_string()).collect();
+        return Ok(format!("No personas found. Add Markdown files to {}\n", dirs.join(" or ")));
+    }
+
+    let mut output = String::new();
+    for persona in personas {
+        let summary = persona.prompt.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim();
+        let summary = match summary.char_indices().nth(72) {
+            Some((end, _)) => format!("{}...", &summary[..end]),
+            None => summary.to_string(),
+        };
+        output.push_str(&format!("{:<20} {}\n", persona.name, summary));
+
+        let fm = &persona.front_matter;
+        let mut defaults = Vec::new();
+        if let Some(model) = &fm.model {
+            defaults.push(format!("model: {}", model));
+        }
+        if let Some(temperature) = fm.temperature {
+            defaults.push(format!("temperature: {}", temperature));
+        }
+        if let Some(max_tokens) = &fm.max_tokens {
+            defaults.push(format!("max_tokens: {}", max_tokens));
+        }
+        if !defaults.is_empty() {
+            output.push_str(&format!("{:<20} ({})\n", "", defaults.join(", ")));
+        }
+        output.push_str(&format!("{:<20} {}\n", "", persona.path.display()));
+    }
+    Ok(output)
+}
@@ -0,0 +1,217 @@
+// Regenerating the last response. An `@regen` directive in the trailing prompt (or
+// `gchat regen`) resends the history up to the last user turn, and the response it
+// replaces is kept in a folded `<details>` block beneath the new one.
+
+use std::fs;
+use std::io::{self, Write as IoWrite};
+use std::path::Path;
+
+use crate::{has_directive, scan_chat_lines, split_front_matter, strip_comments, FenceTracker, Markers};
+
+const ALTERNATIVE_OPEN: &str = r#"<details class="gchat-alternative">"#;
+const DETAILS_CLOSE: &str = "</details>";
+
+// Where the response being regenerated sits in the chat file
+pub struct Regen {
+    // Byte offset of the last response's marker line
+    pub response_start: usize,
+    // The response's visible text, and the earlier alternatives kept under it (newest first)
+    pub previous: String,
+    pub alternatives: Vec<String>,
+}
+
+// Find the last response if the prompt after it asks for `@regen`
+pub fn find_regen(content: &str, markers: &Markers) -> Option<Regen> {
+    let (response_start, body, trailing) = last_response(content, markers)?;
+    if !has_directive(trailing, "@regen") {
+        return None;
+    }
+    let (previous, alternatives) = split_alternatives(body);
+    Some(Regen {
+        response_start,
+        previous,
+        alternatives,
+    })
+}
+
+// Mark the trailing prompt of a chat file with `@regen`, for `gchat regen`
+pub fn request_regen(chat_path: &Path, markers: &Markers) -> io::Result<()> {
+    let content = fs::read_to_string(chat_path)?;
+    let Some((_, _, trailing)) = last_response(&content, markers) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No response to regenerate"));
+    };
+    if has_directive(trailing, "@regen") {
+        return Ok(());
+    }
+    if !strip_comments(trailing).trim().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The last prompt has not been answered yet; there is no response to regenerate",
+        ));
+    }
+    let mut file = fs::OpenOptions::new().append(true).open(chat_path)?;
+    writeln!(file, "@regen")
+}
+
+// The chat file with a regenerated response in place of the last one, given the
+// history before it, ending with an empty prompt
+pub fn replace_response(history: &str, response: &str, regen: &Regen, markers: &Markers) -> String {
+    format!(
+        "{}{}\n{}\n\n{}\n\n",
+        history,
+        markers.grok,
+        render_regenerated(response, regen),
+        markers.user
+    )
+}
+
+// The section body for a regenerated response: the new text, then the replaced
+// response and any earlier alternatives, each folded away
+fn render_regenerated(response: &str, regen: &Regen) -> String {
+    let alternatives: Vec<&str> = std::iter::once(regen.previous.as_str())
+        .chain(regen.alternatives.iter().map(|a| a.as_str()))
+        .filter(|a| !a.is_empty())
+        .collect();
+
+    let mut body = response.to_string();
+    for (i, alternative) in alternatives.iter().enumerate() {
+        body.push_str(&format!(
+            "\n\n{}\n<summary>Earlier response {}</summary>\n\n{}\n\n{}",
+            ALTERNATIVE_OPEN,
+            alternatives.len() - i,
+            alternative,
+            DETAILS_CLOSE
+        ));
+    }
+    body
+}
+
+// Drop folded alternatives from a response, leaving its visible text
+pub fn strip_alternatives(text: &str) -> String {
+    split_alternatives(text).0
+}
+
+// Locate the last response: the byte offset of its marker line, its body, and
+// everything after the prompt marker that follows it
+fn last_response<'a>(content: &'a str, markers: &Markers) -> Option<(usize, &'a str, &'a str)> {
+    let body = split_front_matter(content, Some(markers)).map(|(_, _, body)| body).unwrap_or(content);
+    let mut offset = content.len() - body.len();
+    let mut response: Option<(usize, usize)> = None;
+    let mut prompt: Option<(usize, usize)> = None;
+
+    for (line, role, _) in scan_chat_lines(body, markers) {
+        match role {
+            Some("assistant") => {
+                response = Some((offset, offset + line.len()));
+                prompt = None;
+            }
+            Some(_) if response.is_some() && prompt.is_none() => prompt = Some((offset, offset + line.len())),
+            _ => {}
+        }
+        offset += line.len();
+    }
+
+    let (response_start, body_start) = response?;
+    let (prompt_start, trailing_start) = prompt?;
+    Some((response_start, &content[body_start..prompt_start], &content[trailing_start..]))
+}
+
+// Split a response body into its visible text and its folded alternatives
+fn split_alternatives(text: &str) -> (String, Vec<String>) {
+    let mut fences = FenceTracker::default();
+    let mut visible = String::new();
+    let mut alternatives = Vec::new();
+    let mut current: Option<String> = None;
+    let mut depth = 0;
+
+    for line in text.lines() {
+        let in_code = fences.is_code(line);
+        let trimmed = line.trim();
+        match current.as_mut() {
+            None if !in_code && trimmed == ALTERNATIVE_OPEN => {
+                current = Some(String::new());
+                depth = 1;
+            }
+            None => {
+                visible.push_str(line);
+                visible.push('\n');
+            }
+            Some(alternative) => {
+                if !in_code && trimmed.starts_with("<details") {
+                    depth += 1;
+                } else if !in_code && trimmed == DETAILS_CLOSE {
+                    depth -= 1;
+                    if depth == 0 {
+                        alternatives.push(alternative.trim().to_string());
+                        current = None;
+                        continue;
+                    }
+                }
+                if !(depth == 1 && trimmed.starts_with("<summary>")) {
+                    alternative.push_str(line);
+                    alternative.push('\n');
+                }
+            }
+        }
+    }
+    // An unterminated block still counts as an alternative
+    if let Some(alternative) = current {
+        alternatives.push(alternative.trim().to_string());
+    }
+
+    (visible.trim().to_string(), alternatives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_chat_messages;
+
+    fn plain() -> Markers {
+        Markers::from_style("plain").unwrap()
+    }
+
+    // Ask for a regeneration of the last response, and write the new one in its place
+    fn regenerate(content: &str, response: &str) -> String {
+        let content = format!("{}@regen\n", content);
+        let regen = find_regen(&content, &plain()).expect("a response to regenerate");
+        replace_response(&content[..regen.response_start], response, &regen, &plain())
+    }
+
+    // A response holding its own folded block, and a fenced closing tag
+    const FIRST: &str = "First answer.\n\n<details>\n<summary>Details</summary>\n\nInner text.\n\n</details>\n\n```html\n</details>\n```";
+
+    #[test]
+    fn regenerating_twice_keeps_every_response() {
+        let content = format!("USER PROMPT:\nQuestion\n\nGROK RESPONSE:\n{}\n\nUSER PROMPT:\n", FIRST);
+        let once = regenerate(&content, "Second answer.");
+        let twice = regenerate(&once, "Third answer.");
+        assert!(twice.starts_with("USER PROMPT:\nQuestion\n\nGROK RESPONSE:\nThird answer.\n\n"));
+        assert!(twice.ends_with("</details>\n\nUSER PROMPT:\n\n"));
+        assert!(twice.contains("<summary>Earlier response 2</summary>\n\nSecond answer.\n\n</details>"));
+        assert!(twice.contains(&format!("<summary>Earlier response 1</summary>\n\n{}\n\n</details>", FIRST)));
+
+        let regen = find_regen(&format!("{}@regen\n", twice), &plain()).unwrap();
+        assert_eq!(regen.previous, "Third answer.");
+        assert_eq!(regen.alternatives, ["Second answer.", FIRST]);
+    }
+
+    #[test]
+    fn alternatives_are_never_sent() {
+        let content = format!("USER PROMPT:\nQuestion\n\nGROK RESPONSE:\n{}\n\nUSER PROMPT:\n", FIRST);
+        let twice = regenerate(&regenerate(&content, "Second answer."), "Third answer.");
+        let messages = parse_chat_messages(&format!("{}Next question\n", twice), &plain());
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Question", "Third answer.", "Next question"]);
+        assert_eq!(strip_alternatives(FIRST), FIRST);
+    }
+
+    #[test]
+    fn regen_needs_a_response_and_the_directive() {
+        assert!(find_regen("USER PROMPT:\nQuestion\n@regen\n", &plain()).is_none());
+        assert!(find_regen("USER PROMPT:\nQ\n\nGROK RESPONSE:\nA\n\nUSER PROMPT:\nMore\n", &plain()).is_none());
+        assert!(find_regen("USER PROMPT:\nQ\n\nGROK RESPONSE:\nA\n\nUSER PROMPT:\n```\n@regen\n```\n", &plain()).is_none());
+        let regen = find_regen("USER PROMPT:\nQ\n\nGROK RESPONSE:\nA\n\nUSER PROMPT:\n@regen\n", &plain()).unwrap();
+        assert_eq!((regen.response_start, regen.previous.as_str()), (16, "A"));
+    }
+}
@@ -0,0 +1,287 @@
+// How included files and directory listings are laid out in a prompt: Markdown
+// fences tagged with the file's language (`fenced`, the default), or
+// `<file path="...">` wrappers (`xml`), optionally with line numbers.
+
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum IncludeStyle {
+    #[default]
+    Fenced,
+    Xml,
+}
+
+impl IncludeStyle {
+    pub fn from_name(name: &str) -> Result<IncludeStyle, String> {
+        match name {
+            "fenced" => Ok(IncludeStyle::Fenced),
+            "xml" => Ok(IncludeStyle::Xml),
+            _ => Err(format!("Unknown include style '{}': expected 'fenced' or 'xml'", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rendering {
+    pub style: IncludeStyle,
+    pub line_numbers: bool,
+}
+
+impl fmt::Display for Rendering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let style = match self.style {
+            IncludeStyle::Fenced => "fenced",
+            IncludeStyle::Xml => "xml",
+        };
+        write!(f, "{}", style)?;
+        if self.line_numbers {
+            write!(f, ", with line numbers")?;
+        }
+        Ok(())
+    }
+}
+
+impl Rendering {
+    // Number a file's lines, if line numbers are on; `first_line` is the file's
+    // line number of the text's first line. Done before any trimming, so the
+    // numbers stay those of the file.
+    pub fn number(&self, text: &str, first_line: usize) -> String {
+        if self.line_numbers {
+            number_lines(text, first_line)
+        } else {
+            text.to_string()
+        }
+    }
+
+    // A file's text (or part of it, described by `detail`)
+    pub fn file(&self, path: &Path, detail: Option<&str>, text: &str) -> String {
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        match self.style {
+            IncludeStyle::Fenced => {
+                let label = match detail {
+                    Some(detail) => format!("{} ({})", path.display(), detail),
+                    None => path.display().to_string(),
+                };
+                let fence = fence_for(text);
+                format!("Contents of {}:\n{}{}\n{}\n{}\n\n", label, fence, language_of(path), text, fence)
+            }
+            IncludeStyle::Xml => {
+                let detail = detail.map(|d| format!(" range=\"{}\"", escape_attr(d))).unwrap_or_default();
+                format!("<file path=\"{}\"{}>\n{}\n</file>\n\n", escape_attr(&path.display().to_string()), detail, text)
+            }
+        }
+    }
+
+    // A unified diff of a file against the copy included earlier in the conversation
+    pub fn changes(&self, path: &Path, detail: Option<&str>, diff: &str) -> String {
+        let diff = diff.strip_suffix('\n').unwrap_or(diff);
+        match self.style {
+            IncludeStyle::Fenced => {
+                let label = match detail {
+                    Some(detail) => format!("{} ({})", path.display(), detail),
+                    None => path.display().to_string(),
+                };
+                let fence = fence_for(diff);
+                format!("Changes to {} since the earlier copy above:\n{}diff\n{}\n{}\n\n", label, fence, diff, fence)
+            }
+            IncludeStyle::Xml => {
+                let detail = detail.map(|d| format!(" range=\"{}\"", escape_attr(d))).unwrap_or_default();
+                format!(
+                    "<file-changes path=\"{}\"{}>\n{}\n</file-changes>\n\n",
+                    escape_attr(&path.display().to_string()),
+                    detail,
+                    diff
+                )
+            }
+        }
+    }
+
+    // A file included without its contents, e.g. "binary, 18342 bytes"
+    pub fn note(&self, path: &Path, note: &str) -> String {
+        match self.style {
+            IncludeStyle::Fenced => format!("Contents of {}: ({})\n\n", path.display(), note),
+            IncludeStyle::Xml => format!(
+                "<file path=\"{}\" note=\"{}\" />\n\n",
+                escape_attr(&path.display().to_string()),
+                escape_attr(note)
+            ),
+        }
+    }
+
+    // A page fetched by `@url`, as text
+    pub fn page(&self, url: &str, language: &str, text: &str) -> String {
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        match self.style {
+            IncludeStyle::Fenced => {
+                let fence = fence_for(text);
+                format!("Contents of {}:\n{}{}\n{}\n{}\n\n", url, fence, language, text, fence)
+            }
+            IncludeStyle::Xml => format!("<page url=\"{}\">\n{}\n</page>\n\n", escape_attr(url), text),
+        }
+    }
+
+    // The output of a command, e.g. `git diff`
+    pub fn output(&self, command: &str, language: &str, text: &str) -> String {
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let text = if text.is_empty() { "(no output)" } else { text };
+        match self.style {
+            IncludeStyle::Fenced => {
+                let fence = fence_for(text);
+                format!("Output of `{}`:\n{}{}\n{}\n{}\n\n", command, fence, language, text, fence)
+            }
+            IncludeStyle::Xml => format!("<output command=\"{}\">\n{}\n</output>\n\n", escape_attr(command), text),
+        }
+    }
+
+    // A directory listing from `@d`
+    pub fn directory(&self, path: &Path, listing: &str) -> String {
+        let listing = listing.strip_suffix('\n').unwrap_or(listing);
+        match self.style {
+            IncludeStyle::Fenced => {
+                let fence = fence_for(listing);
+                format!("Contents of directory {}:\n{}\n{}\n{}\n", path.display(), fence, listing, fence)
+            }
+            IncludeStyle::Xml => format!(
+                "<directory path=\"{}\">\n{}\n</directory>\n",
+                escape_attr(&path.display().to_string()),
+                listing
+            ),
+        }
+    }
+}
+
+// Prefix each line with its number, right-aligned, as `  42 | text`
+fn number_lines(text: &str, first_line: usize) -> String {
+    let last_line = first_line + text.lines().count().saturating_sub(1);
+    let width = last_line.to_string().len();
+    let mut numbered = String::with_capacity(text.len() + text.lines().count() * (width + 3));
+    for (i, line) in text.lines().enumerate() {
+        numbered.push_str(&format!("{:>width$} | {}\n", first_line + i, line, width = width));
+    }
+    numbered
+}
+
+// A backtick fence longer than any backtick run in the text, so it can't be closed early
+fn fence_for(text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        if c == '`' {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    "`".repeat((longest + 1).max(3))
+}
+
+// The fence info string for a file, from its extension or name
+fn language_of(path: &Path) -> &'static str {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    match name {
+        "Dockerfile" => return "dockerfile",
+        "Makefile" | "makefile" => return "makefile",
+        "Cargo.lock" => return "toml",
+        _ => {}
+    }
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "sh" | "bash" | "zsh" => "bash",
+        "ps1" => "powershell",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "json" => "json",
+        "xml" => "xml",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "sql" => "sql",
+        "md" | "markdown" => "markdown",
+        "lua" => "lua",
+        "hs" => "haskell",
+        "ex" | "exs" => "elixir",
+        "erl" => "erlang",
+        "scala" => "scala",
+        "dart" => "dart",
+        "proto" => "protobuf",
+        "diff" | "patch" => "diff",
+        _ => "",
+    }
+}
+
+fn escape_attr(text: &str) -> String {
+    text.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fences_outlast_backticks_in_the_text() {
+        assert_eq!(fence_for("plain text"), "```");
+        assert_eq!(fence_for("`code` and ``more``"), "```");
+        assert_eq!(fence_for("```rust\nfn main() {}\n```"), "````");
+        assert_eq!(fence_for("````\nnested\n````"), "`````");
+
+        // A Markdown file with a fenced block keeps it, inside a longer fence
+        let readme = "# Usage\n```\ncargo run\n```\n";
+        let rendered = Rendering::default().file(Path::new("README.md"), None, readme);
+        assert_eq!(rendered, "Contents of README.md:\n````markdown\n# Usage\n```\ncargo run\n```\n````\n\n");
+    }
+
+    #[test]
+    fn languages() {
+        for (path, language) in [
+            ("src/main.rs", "rust"),
+            ("a/b.PY", "python"),
+            ("web/app.tsx", "tsx"),
+            ("Dockerfile", "dockerfile"),
+            ("Makefile", "makefile"),
+            ("Cargo.lock", "toml"),
+            ("fix.patch", "diff"),
+            ("notes.txt", ""),
+            ("LICENSE", ""),
+        ] {
+            assert_eq!(language_of(Path::new(path)), language, "{}", path);
+        }
+    }
+
+    #[test]
+    fn line_numbers() {
+        assert_eq!(number_lines("a\nb\n", 1), "1 | a\n2 | b\n");
+        assert_eq!(number_lines("x\ny\nz", 8), " 8 | x\n 9 | y\n10 | z\n");
+        let rendering = Rendering { line_numbers: false, ..Rendering::default() };
+        assert_eq!(rendering.number("a\n", 5), "a\n");
+    }
+
+    #[test]
+    fn xml_attributes_are_escaped() {
+        assert_eq!(escape_attr(r#"a & "b" <c>"#), "a &amp; &quot;b&quot; &lt;c&gt;");
+        let xml = Rendering { style: IncludeStyle::Xml, line_numbers: false };
+        assert_eq!(
+            xml.file(Path::new("a\"b.rs"), Some("lines 1-2 of 9"), "x\n"),
+            "<file path=\"a&quot;b.rs\" range=\"lines 1-2 of 9\">\nx\n</file>\n\n"
+        );
+        assert_eq!(
+            xml.output("grep '<T>' src", "", "hit\n"),
+            "<output command=\"grep '&lt;T&gt;' src\">\nhit\n</output>\n\n"
+        );
+    }
+}
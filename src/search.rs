@@ -0,0 +1,284 @@
+// Full-text search across chat files and their archives. Each chat file is parsed
+// into turns with the regular parser, and an inverted index of its words is kept
+// on disk, refreshed only for files that changed since the last search.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{chat_sections, display_path, parse_chat_messages, write_atomically, Markers};
+
+// Bump when the index layout changes, so stale indexes are rebuilt
+const INDEX_VERSION: u32 = 1;
+const SNIPPET_CONTEXT: usize = 60;
+
+#[derive(Serialize, Deserialize, Default)]
+struct SearchIndex {
+    version: u32,
+    files: BTreeMap<PathBuf, IndexedFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedFile {
+    modified: u64,
+    len: u64,
+    turns: Vec<IndexedTurn>,
+    // Each word, and the turns (indexes into `turns`) it appears in
+    terms: BTreeMap<String, Vec<usize>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedTurn {
+    role: String,
+    content: String,
+}
+
+pub struct SearchOptions {
+    pub roots: Vec<PathBuf>,
+    pub role: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub reindex: bool,
+}
+
+// Search every chat file under the roots, returning the formatted results
+pub fn search(query: &str, options: &SearchOptions, markers: &Markers) -> io::Result<String> {
+    let terms: Vec<String> = tokenize(query).into_iter().collect();
+    if terms.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Search query has no words"));
+    }
+
+    let index_path = index_path();
+    let mut index = if options.reindex { SearchIndex::default() } else { load_index(&index_path) };
+    let updated = refresh_index(&mut index, &options.roots, markers)?;
+    if updated > 0 {
+        if let Some(dir) = index_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string(&index).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomically(&index_path, &json)?;
+    }
+
+    let since = options
+        .since
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc().timestamp() as u64);
+    let highlight = io::stdout().is_terminal();
+    let roots: Vec<PathBuf> = options.roots.iter().filter_map(|root| root.canonicalize().ok()).collect();
+
+    let mut output = String::new();
+    let mut matches = 0;
+    let mut searched = 0;
+    for (path, file) in &index.files {
+        if !roots.iter().any(|root| path.starts_with(root)) || since.is_some_and(|since| file.modified < since) {
+            continue;
+        }
+        searched += 1;
+        // Turns containing every word of the query
+        let mut candidates: Option<BTreeSet<usize>> = None;
+        for term in &terms {
+            let turns: BTreeSet<usize> = file.terms.get(term).into_iter().flatten().copied().collect();
+            candidates = Some(match candidates {
+                Some(found) => found.intersection(&turns).copied().collect(),
+                None => turns,
+            });
+        }
+
+        for turn in candidates.unwrap_or_default() {
+            let indexed = &file.turns[turn];
+            if options.role.as_ref().is_some_and(|role| *role != indexed.role) {
+                continue;
+            }
+            matches += 1;
+            output.push_str(&format!(
+                "{}:{} [{}]\n    {}\n",
+                display_path(path),
+                turn + 1,
+                indexed.role,
+                snippet(&indexed.content, &terms, highlight)
+            ));
+        }
+    }
+
+    output.push_str(&format!(
+        "{} matching turn{} in {} chat file{} searched\n",
+        matches,
+        if matches == 1 { "" } else { "s" },
+        searched,
+        if searched == 1 { "" } else { "s" }
+    ));
+    Ok(output)
+}
+
+// Bring the index up to date with the chat files under the roots, returning how
+// many files were (re)indexed or dropped
+fn refresh_index(index: &mut SearchIndex, roots: &[PathBuf], markers: &Markers) -> io::Result<usize> {
+    if index.version != INDEX_VERSION {
+        *index = SearchIndex {
+            version: INDEX_VERSION,
+            ..SearchIndex::default()
+        };
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut updated = 0;
+    for root in roots {
+        let entries = WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !is_skipped_dir(e.file_name().to_str().unwrap_or("")))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && e.path().extension().and_then(|x| x.to_str()) == Some("md"));
+
+        for entry in entries {
+            let Ok(path) = entry.path().canonicalize() else {
+                continue;
+            };
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let modified = meta
+                .modified()
+                .unwrap_or(SystemTime::now())
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            seen.insert(path.clone());
+
+            if index.files.get(&path).is_some_and(|f| f.modified == modified && f.len == meta.len()) {
+                continue;
+            }
+            // Unreadable files and Markdown that isn't a chat are left out
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            if chat_sections(&content, markers).is_empty() {
+                index.files.remove(&path);
+                continue;
+            }
+            index.files.insert(path, index_file(&content, modified, meta.len(), markers));
+            updated += 1;
+        }
+    }
+
+    // Forget files under the roots that no longer exist
+    let roots: Vec<PathBuf> = roots.iter().filter_map(|root| root.canonicalize().ok()).collect();
+    let before = index.files.len();
+    index
+        .files
+        .retain(|path, _| seen.contains(path) || !roots.iter().any(|root| path.starts_with(root)));
+    Ok(updated + before - index.files.len())
+}
+
+fn index_file(content: &str, modified: u64, len: u64, markers: &Markers) -> IndexedFile {
+    let mut terms: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let turns: Vec<IndexedTurn> = parse_chat_messages(content, markers)
+        .into_iter()
+        .map(|msg| IndexedTurn {
+            role: msg.role,
+            content: msg.content,
+        })
+        .collect();
+    for (i, turn) in turns.iter().enumerate() {
+        for term in tokenize(&turn.content) {
+            terms.entry(term).or_default().push(i);
+        }
+    }
+    IndexedFile {
+        modified,
+        len,
+        turns,
+        terms,
+    }
+}
+
+// Lowercased words of letters, digits and underscores
+fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// A line of context around the first match, with every query word highlighted
+fn snippet(content: &str, terms: &[String], highlight: bool) -> String {
+    let flat: String = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    let lower = flat.to_lowercase();
+    // Lowercasing can change byte lengths; fall back to the start if it did
+    let first = if lower.len() == flat.len() {
+        terms.iter().filter_map(|term| lower.find(term.as_str())).min().unwrap_or(0)
+    } else {
+        0
+    };
+
+    let start = floor_char_boundary(&flat, first.saturating_sub(SNIPPET_CONTEXT));
+    let end = floor_char_boundary(&flat, (first + SNIPPET_CONTEXT * 2).min(flat.len()));
+    let mut text = flat[start..end].to_string();
+    if start > 0 {
+        text = format!("...{}", text);
+    }
+    if end < flat.len() {
+        text.push_str("...");
+    }
+
+    if lower.len() != flat.len() {
+        return text;
+    }
+    let text_lower = text.to_lowercase();
+    let mut marked = String::new();
+    let mut i = 0;
+    while i < text.len() {
+        let hit = terms.iter().find(|term| text_lower[i..].starts_with(term.as_str()));
+        match hit {
+            Some(term) => {
+                let word = &text[i..i + term.len()];
+                if highlight {
+                    marked.push_str(&format!("\x1b[1;33m{}\x1b[0m", word));
+                } else {
+                    marked.push_str(&format!("**{}**", word));
+                }
+                i += term.len();
+            }
+            None => {
+                let ch = text[i..].chars().next().unwrap();
+                marked.push(ch);
+                i += ch.len_utf8();
+            }
+        }
+    }
+    marked
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn is_skipped_dir(name: &str) -> bool {
+    name.starts_with('.') || name == "target" || name == "node_modules"
+}
+
+fn index_path() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("gchat/search-index.json")
+}
+
+fn load_index(path: &Path) -> SearchIndex {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+// Parse a `--since` date (YYYY-MM-DD)
+pub fn parse_since(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|e| format!("Invalid date '{}': expected YYYY-MM-DD ({})", value, e))
+}
@@ -0,0 +1,227 @@
+// The `@sh:"command"` placeholder: runs a command and includes its output, stderr
+// and exit code. It's off unless the config file allows some commands with
+// `shell_allow`, glob patterns the whole command must match (e.g. "cargo test*").
+// Commands run directly rather than through a shell, so pipes, redirects and `;`
+// are just arguments, and an allowed prefix can't be chained into something else.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use glob::Pattern;
+
+pub const DEFAULT_TIMEOUT: u64 = 30;
+pub const DEFAULT_MAX_OUTPUT: u64 = 100_000;
+// How long to wait for output after the command exits or is killed, in case a
+// child process it started still holds the pipes open
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ShellPolicy {
+    pub allow: Vec<String>,
+    // Seconds
+    pub timeout: u64,
+    // Bytes kept of each of stdout and stderr
+    pub max_output: u64,
+}
+
+impl fmt::Display for ShellPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.allow.is_empty() {
+            return write!(f, "off");
+        }
+        write!(
+            f,
+            "allowed: {} (timeout {}s, output up to {} bytes)",
+            self.allow.join(", "),
+            self.timeout,
+            self.max_output
+        )
+    }
+}
+
+impl ShellPolicy {
+    fn allows(&self, command: &str) -> bool {
+        self.allow.iter().any(|pattern| Pattern::new(pattern).is_ok_and(|p| p.matches(command)))
+    }
+
+    // Run an allowed command, returning a transcript of what it printed and how it exited
+    pub fn run(&self, command: &str) -> io::Result<String> {
+        let command = command.trim();
+        if self.allow.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "@sh is disabled; allow commands with shell_allow in the config file",
+            ));
+        }
+        if !self.allows(command) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("'{}' doesn't match any shell_allow pattern", command),
+            ));
+        }
+        println!("Running '{}' for @sh", command);
+        let words = split_words(command)?;
+        let Some((program, args)) = words.split_first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty command"));
+        };
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to run '{}': {}", program, e)))?;
+        let stdout = capture(child.stdout.take().expect("stdout is piped"), self.max_output);
+        let stderr = capture(child.stderr.take().expect("stderr is piped"), self.max_output);
+
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let mut transcript = String::new();
+        let (stdout, stdout_len) = stdout.recv_timeout(OUTPUT_GRACE).unwrap_or_default();
+        push_stream(&mut transcript, None, &stdout, stdout_len);
+        let (stderr, stderr_len) = stderr.recv_timeout(OUTPUT_GRACE).unwrap_or_default();
+        push_stream(&mut transcript, Some("stderr"), &stderr, stderr_len);
+        match status {
+            Some(status) => match status.code() {
+                Some(code) => transcript.push_str(&format!("[exit code {}]\n", code)),
+                None => transcript.push_str("[killed by a signal]\n"),
+            },
+            None => transcript.push_str(&format!("[timed out after {}s and was killed]\n", self.timeout)),
+        }
+        Ok(transcript)
+    }
+}
+
+// Read a stream on its own thread, keeping up to `max` bytes and counting the rest
+fn capture(mut reader: impl Read + Send + 'static, max: u64) -> mpsc::Receiver<(Vec<u8>, u64)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let _ = reader.by_ref().take(max).read_to_end(&mut kept);
+        let rest = io::copy(&mut reader, &mut io::sink()).unwrap_or(0);
+        let total = kept.len() as u64 + rest;
+        let _ = sender.send((kept, total));
+    });
+    receiver
+}
+
+fn push_stream(transcript: &mut String, name: Option<&str>, bytes: &[u8], total: u64) {
+    if bytes.is_empty() {
+        return;
+    }
+    if let Some(name) = name {
+        transcript.push_str(&format!("[{}]\n", name));
+    }
+    transcript.push_str(&String::from_utf8_lossy(bytes));
+    if !transcript.ends_with('\n') {
+        transcript.push('\n');
+    }
+    if total > bytes.len() as u64 {
+        transcript.push_str(&format!("[... {} more bytes not captured ...]\n", total - bytes.len() as u64));
+    }
+}
+
+// Split a command line into words, honouring single and double quotes and backslash escapes
+fn split_words(command: &str) -> io::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                in_word = true;
+            }
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unclosed quote in '{}'", command)));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &str) -> Vec<String> {
+        split_words(command).unwrap()
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(words("  cargo   test -p core "), ["cargo", "test", "-p", "core"]);
+        assert_eq!(words(r#"grep "two words" 'it"s' a\ b"#), ["grep", "two words", "it\"s", "a b"]);
+        assert_eq!(words(r#"echo "a\"b" 'c\d' """#), ["echo", "a\"b", "c\\d", ""]);
+        // Shell syntax is just more arguments
+        assert_eq!(words("ls; rm -rf x | cat"), ["ls;", "rm", "-rf", "x", "|", "cat"]);
+        assert!(split_words("echo 'open").is_err());
+    }
+
+    #[test]
+    fn allowlist() {
+        let policy = ShellPolicy {
+            allow: vec!["cargo test*".to_string(), "git status".to_string()],
+            timeout: 5,
+            max_output: 100,
+        };
+        assert!(policy.allows("cargo test -p core"));
+        assert!(policy.allows("git status"));
+        assert!(!policy.allows("git status; rm -rf /"));
+        assert!(!policy.allows("cargo build"));
+        let denied = policy.run("cargo build").unwrap_err();
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+        let off = ShellPolicy { allow: Vec::new(), ..policy };
+        assert_eq!(off.run("git status").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn transcript_caps_output_and_reports_exit_code() {
+        let policy = ShellPolicy {
+            allow: vec!["sh *".to_string()],
+            timeout: 5,
+            max_output: 4,
+        };
+        let transcript = policy.run("sh -c 'echo 123456789; echo err >&2; exit 3'").unwrap();
+        assert_eq!(transcript, "1234\n[... 6 more bytes not captured ...]\n[stderr]\nerr\n[exit code 3]\n");
+    }
+}
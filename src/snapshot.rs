@@ -0,0 +1,332 @@
+// Snapshots of included files, so history stays as the model first saw it. The
+// prompt being sent always reads its files from disk; once a response to it arrives,
+// each file it read is frozen into a content-addressed store under
+// `.gchat/snapshots/`, and later sends, where it is an earlier prompt, read the frozen
+// copy instead. A send that fails freezes nothing, so fixing a file and saving again
+// sends the fix. `@refresh` in the prompt being sent discards a chat's snapshots, so
+// every turn is read afresh (and re-frozen).
+// The store also keeps the output of each prompt's `@sh` commands, which only run
+// in the prompt being sent; that is kept whether or not files are frozen.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Message;
+
+const SNAPSHOT_DIR: &str = ".gchat/snapshots";
+
+// Which file contents each prompt of a chat was sent with
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    chat: String,
+    // Prompt key -> file path -> object hash
+    turns: BTreeMap<String, BTreeMap<String, String>>,
+    // Prompt key -> command -> object hash of its transcript
+    #[serde(default)]
+    commands: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+pub struct Snapshots {
+    root: PathBuf,
+    manifest_path: PathBuf,
+    manifest: Manifest,
+    // Whether file reads go through the store, or just command output
+    files: bool,
+    // How many prompts with each content hash have been seen in this send, so
+    // identical prompts in different turns get their own snapshots
+    occurrences: HashMap<String, usize>,
+    // Prompts sent this time; the rest are dropped from the manifest on save
+    current: HashSet<String>,
+    turn: Option<String>,
+    // Whether the current prompt is the one being sent, which reads files from disk
+    sending: bool,
+    pub frozen_reads: usize,
+    pub new_reads: usize,
+}
+
+impl Snapshots {
+    pub fn open(chat_path: &Path, files: bool) -> io::Result<Snapshots> {
+        Snapshots::open_in(PathBuf::from(SNAPSHOT_DIR), chat_path, files)
+    }
+
+    fn open_in(root: PathBuf, chat_path: &Path, files: bool) -> io::Result<Snapshots> {
+        let chat = crate::display_path(&chat_path.canonicalize()?);
+        let manifest_path = root.join("chats").join(format!("{}.json", hash(chat.as_bytes())));
+        let manifest = match fs::read_to_string(&manifest_path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", manifest_path.display(), e))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest {
+                chat,
+                ..Manifest::default()
+            },
+            Err(e) => return Err(e),
+        };
+        Ok(Snapshots {
+            root,
+            manifest_path,
+            manifest,
+            files,
+            occurrences: HashMap::new(),
+            current: HashSet::new(),
+            turn: None,
+            sending: false,
+            frozen_reads: 0,
+            new_reads: 0,
+        })
+    }
+
+    // Forget every file snapshot of this chat, for `@refresh`. Command output stays,
+    // as earlier prompts' commands aren't run again.
+    pub fn clear(&mut self) {
+        self.manifest.turns.clear();
+    }
+
+    // Start expanding a prompt; reads until the next call belong to it
+    pub fn begin_turn(&mut self, prompt: &str, sending: bool) {
+        let content = hash(prompt.as_bytes());
+        let occurrence = self.occurrences.entry(content.clone()).or_insert(0);
+        *occurrence += 1;
+        let key = format!("{}-{}", content, occurrence);
+        self.current.insert(key.clone());
+        self.turn = Some(key);
+        self.sending = sending;
+    }
+
+    // Whether the current prompt has a frozen copy of a file
+    pub fn has(&self, path: &Path) -> bool {
+        self.files && !self.sending && self.frozen_hash(path).is_some()
+    }
+
+    // Read a file as the current prompt was sent with it. The prompt being sent reads
+    // it from disk, to be frozen if the send succeeds.
+    pub fn read(&mut self, path: &Path) -> io::Result<Vec<u8>> {
+        let Some(turn) = self.turn.clone().filter(|_| self.files) else {
+            return fs::read(path);
+        };
+        if let Some(object) = self.frozen_hash(path).filter(|_| !self.sending) {
+            match fs::read(self.object_path(object)) {
+                Ok(bytes) => {
+                    self.frozen_reads += 1;
+                    return Ok(bytes);
+                }
+                Err(e) => println!("Warning: Snapshot of {} is missing ({}), reading it again", path.display(), e),
+            }
+        }
+
+        let bytes = fs::read(path)?;
+        let object = self.store(&bytes)?;
+        self.manifest.turns.entry(turn).or_default().insert(key_of(path), object);
+        self.new_reads += 1;
+        Ok(bytes)
+    }
+
+    // The output a command printed when the current prompt was sent, if it was kept
+    pub fn command_output(&self, command: &str) -> Option<String> {
+        let object = self.manifest.commands.get(self.turn.as_ref()?)?.get(command)?;
+        fs::read_to_string(self.object_path(object)).ok()
+    }
+
+    // Keep the output a command printed for the current prompt
+    pub fn keep_command_output(&mut self, command: &str, output: &str) -> io::Result<()> {
+        let Some(turn) = self.turn.clone() else {
+            return Ok(());
+        };
+        let object = self.store(output.as_bytes())?;
+        self.manifest.commands.entry(turn).or_default().insert(command.to_string(), object);
+        Ok(())
+    }
+
+    // Write the manifest once the prompts have been sent, keeping only those prompts
+    pub fn save(&mut self) -> io::Result<()> {
+        let current = &self.current;
+        self.manifest.turns.retain(|turn, _| current.contains(turn));
+        self.manifest.commands.retain(|turn, _| current.contains(turn));
+        // A chat with nothing stored needs no manifest
+        if self.manifest.turns.is_empty() && self.manifest.commands.is_empty() {
+            return match fs::remove_file(&self.manifest_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        if let Some(parent) = self.manifest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_string_pretty(&self.manifest).map_err(io::Error::other)?;
+        fs::write(&self.manifest_path, json)
+    }
+
+    fn frozen_hash(&self, path: &Path) -> Option<&String> {
+        self.manifest.turns.get(self.turn.as_ref()?)?.get(&key_of(path))
+    }
+
+    // Add content to the object store, returning its hash
+    fn store(&self, bytes: &[u8]) -> io::Result<String> {
+        let object = hash(bytes);
+        let object_path = self.object_path(&object);
+        if !object_path.exists() {
+            if let Some(parent) = object_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&object_path, bytes)?;
+        }
+        Ok(object)
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.root.join("objects").join(&object[..2]).join(&object[2..])
+    }
+}
+
+// Strip `@refresh` directives, returning whether the prompt being sent has one
+pub fn take_refresh(messages: &mut [Message]) -> bool {
+    let re = Regex::new(r"@refresh\b").unwrap();
+    let last = messages.len().saturating_sub(1);
+    let mut refresh = false;
+    for (i, msg) in messages.iter_mut().enumerate().filter(|(_, m)| m.role == "user") {
+        if re.is_match(&msg.content) {
+            refresh |= i == last;
+            msg.content = re.replace_all(&msg.content, "").to_string();
+        }
+    }
+    refresh
+}
+
+// `./src/main.rs` and `src/main.rs` are the same file
+fn key_of(path: &Path) -> String {
+    path.strip_prefix(".").unwrap_or(path).display().to_string()
+}
+
+fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A chat file, a file it includes, and a store, in a scratch directory
+    fn scratch(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("gchat-snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("chat.md"), "").unwrap();
+        fs::write(dir.join("a.rs"), "one").unwrap();
+        (dir.join("chat.md"), dir.join("a.rs"), dir.join("store"))
+    }
+
+    fn read(snapshots: &mut Snapshots, prompt: &str, sending: bool, path: &Path) -> String {
+        snapshots.begin_turn(prompt, sending);
+        String::from_utf8(snapshots.read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn earlier_prompts_read_what_they_were_sent_with() {
+        let (chat, file, store) = scratch("frozen");
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", true, &file), "one");
+        snapshots.save().unwrap();
+
+        fs::write(&file, "two").unwrap();
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", false, &file), "one");
+        assert!(snapshots.has(&file));
+        assert_eq!(read(&mut snapshots, "second", true, &file), "two");
+        assert_eq!((snapshots.frozen_reads, snapshots.new_reads), (1, 1));
+    }
+
+    #[test]
+    fn the_prompt_being_sent_reads_from_disk() {
+        let (chat, file, store) = scratch("sending");
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", true, &file), "one");
+        snapshots.save().unwrap();
+
+        // Sent again (say, regenerated) it reads the file as it is now
+        fs::write(&file, "two").unwrap();
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", true, &file), "two");
+        assert!(!snapshots.has(&file));
+    }
+
+    #[test]
+    fn a_failed_send_freezes_nothing() {
+        let (chat, file, store) = scratch("failed");
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", true, &file), "one");
+        drop(snapshots);
+
+        // The retry, after fixing the file, sends the fix; and so does a later send
+        fs::write(&file, "fixed").unwrap();
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", true, &file), "fixed");
+        snapshots.save().unwrap();
+        let mut snapshots = Snapshots::open_in(store, &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "first", false, &file), "fixed");
+    }
+
+    #[test]
+    fn identical_prompts_are_kept_apart() {
+        let (chat, file, store) = scratch("identical");
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "again", true, &file), "one");
+        snapshots.save().unwrap();
+
+        fs::write(&file, "two").unwrap();
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        assert_eq!(read(&mut snapshots, "again", false, &file), "one");
+        assert_eq!(read(&mut snapshots, "again", true, &file), "two");
+        snapshots.save().unwrap();
+        assert_eq!(snapshots.manifest.turns.len(), 2);
+    }
+
+    #[test]
+    fn save_keeps_only_current_prompts() {
+        let (chat, file, store) = scratch("retention");
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        read(&mut snapshots, "old", true, &file);
+        snapshots.keep_command_output("date", "Monday").unwrap();
+        snapshots.save().unwrap();
+
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        snapshots.begin_turn("old", false);
+        assert_eq!(snapshots.command_output("date").as_deref(), Some("Monday"));
+        read(&mut snapshots, "new", true, &file);
+        snapshots.save().unwrap();
+        assert_eq!((snapshots.manifest.turns.len(), snapshots.manifest.commands.len()), (2, 1));
+
+        // The old prompt is gone from the chat (say, archived)
+        let mut snapshots = Snapshots::open_in(store.clone(), &chat, true).unwrap();
+        read(&mut snapshots, "new", false, &file);
+        snapshots.save().unwrap();
+        let turns: Vec<&String> = snapshots.manifest.turns.keys().collect();
+        assert_eq!(turns, [&format!("{}-1", hash(b"new"))]);
+        assert!(snapshots.manifest.commands.is_empty());
+
+        // Nothing left to keep: no manifest
+        let mut snapshots = Snapshots::open_in(store, &chat, true).unwrap();
+        snapshots.begin_turn("newer", true);
+        snapshots.save().unwrap();
+        assert!(!snapshots.manifest_path.exists());
+    }
+
+    #[test]
+    fn refresh_only_in_the_prompt_being_sent() {
+        let prompt = |role: &str, content: &str| Message { role: role.to_string(), content: content.to_string() };
+        let mut messages = vec![prompt("user", "@refresh old"), prompt("assistant", "@refresh"), prompt("user", "new")];
+        assert!(!take_refresh(&mut messages));
+        assert_eq!(messages[0].content, " old");
+        assert_eq!(messages[1].content, "@refresh");
+
+        messages.push(prompt("assistant", "ok"));
+        messages.push(prompt("user", "again @refresh"));
+        assert!(take_refresh(&mut messages));
+        assert_eq!(messages[4].content, "again ");
+    }
+}
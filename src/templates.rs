@@ -0,0 +1,180 @@
+// Prompt templates: Markdown files in `~/.config/gchat/templates/` or a project's
+// `.gchat/templates/` with `{{param}}` slots, invoked inline as
+// `@tpl:review file=src/main.rs focus="error handling"`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use crate::{library_dirs, library_entries, library_file};
+
+// A `{{param}}` slot in a template
+const SLOT_PATTERN: &str = r"\{\{\s*([\w-]+)\s*\}\}";
+
+// Replace every `@tpl:` invocation in a prompt with its filled-in template. The
+// result may contain `@f`/`@d` placeholders and directives, which take effect
+// afterwards. A template that can't be filled in stops the prompt being sent
+// (`sending`); in an earlier prompt it's left as written, with a warning.
+pub fn expand_templates(text: &str, sending: bool) -> io::Result<String> {
+    expand_with(text, sending, &|name| library_file("templates", name))
+}
+
+// `find` locates a template's file by name
+fn expand_with(text: &str, sending: bool, find: &dyn Fn(&str) -> Option<PathBuf>) -> io::Result<String> {
+    let re = Regex::new(r#"@tpl\s*:\s*([\w.-]+)((?:[ \t]+[\w-]+=(?:"[^"]*"|\S+))*)"#).unwrap();
+    let re_arg = Regex::new(r#"([\w-]+)=(?:"([^"]*)"|(\S+))"#).unwrap();
+
+    let mut result = String::new();
+    let mut last_end = 0;
+    for cap in re.captures_iter(text) {
+        let whole = cap.get(0).unwrap();
+        result.push_str(&text[last_end..whole.start()]);
+
+        let name = &cap[1];
+        let args: Vec<(&str, &str)> = re_arg
+            .captures_iter(&cap[2])
+            .map(|arg| {
+                let value = arg.get(2).or(arg.get(3)).unwrap().as_str();
+                (arg.get(1).unwrap().as_str(), value)
+            })
+            .collect();
+        match render(name, find(name), &args) {
+            Ok(filled) => result.push_str(&filled),
+            Err(e) if sending => return Err(e),
+            Err(e) => {
+                println!("Warning: Failed to expand template '{}' in an earlier prompt: {}", whole.as_str().trim(), e);
+                result.push_str(whole.as_str());
+            }
+        }
+        last_end = whole.end();
+    }
+    result.push_str(&text[last_end..]);
+    Ok(result)
+}
+
+fn render(name: &str, path: Option<PathBuf>, args: &[(&str, &str)]) -> io::Result<String> {
+    let path = path.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Template '{}' not found (run `gchat --list-templates` to see what's available)", name),
+        )
+    })?;
+    let template = fs::read_to_string(&path)?;
+    let params = params_of(&template);
+
+    let missing: Vec<&str> = params
+        .iter()
+        .map(|p| p.as_str())
+        .filter(|p| !args.iter().any(|(key, _)| key == p))
+        .collect();
+    if !missing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Template '{}' is missing parameter{} {} (usage: {})",
+                name,
+                if missing.len() == 1 { "" } else { "s" },
+                missing.join(", "),
+                usage(name, &params)
+            ),
+        ));
+    }
+    for (key, _) in args.iter().filter(|(key, _)| !params.contains(*key)) {
+        println!("Warning: Template '{}' has no parameter '{}'; ignoring it", name, key);
+    }
+
+    let slot = Regex::new(SLOT_PATTERN).unwrap();
+    let filled = slot.replace_all(template.trim(), |cap: &regex::Captures| {
+        // The last value given for a parameter wins
+        args.iter().rev().find(|(key, _)| *key == &cap[1]).map(|(_, value)| value.to_string()).unwrap_or_default()
+    });
+    Ok(filled.to_string())
+}
+
+// The parameter names a template uses, sorted
+fn params_of(template: &str) -> BTreeSet<String> {
+    let slot = Regex::new(SLOT_PATTERN).unwrap();
+    slot.captures_iter(template).map(|cap| cap[1].to_string()).collect()
+}
+
+fn usage(name: &str, params: &BTreeSet<String>) -> String {
+    let mut usage = format!("@tpl:{}", name);
+    for param in params {
+        usage.push_str(&format!(" {}=...", param));
+    }
+    usage
+}
+
+// A listing of the available templates for `--list-templates`
+pub fn describe_templates() -> io::Result<String> {
+    let templates = library_entries("templates");
+    if templates.is_empty() {
+        let dirs: Vec<String> = library_dirs("templates").iter().map(|d| d.display().to_string()).collect();
+        return Ok(format!("No templates found. Add Markdown files to {}\n", dirs.join(" or ")));
+    }
+
+    let mut output = String::new();
+    for (name, path) in templates {
+        let template = fs::read_to_string(&path)?;
+        output.push_str(&format!("{}\n    {}\n", usage(&name, &params_of(&template)), path.display()));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expand with these templates, and no others
+    fn expand(templates: &[(&str, &str)], text: &str, sending: bool) -> io::Result<String> {
+        let dir = std::env::temp_dir().join(format!("gchat-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<(String, PathBuf)> = templates
+            .iter()
+            .map(|(name, template)| {
+                let path = dir.join(format!("{}.md", name));
+                fs::write(&path, template).unwrap();
+                (name.to_string(), path)
+            })
+            .collect();
+        expand_with(text, sending, &|name| paths.iter().find(|(n, _)| n == name).map(|(_, path)| path.clone()))
+    }
+
+    const REVIEW: &str = "Review {{file}}, focusing on {{ focus }}.\n";
+
+    #[test]
+    fn fills_parameters() {
+        let expanded = expand(&[("review", REVIEW)], "Please: @tpl:review file=@f:src/main.rs focus=\"error handling\" then stop", true);
+        assert_eq!(expanded.unwrap(), "Please: Review @f:src/main.rs, focusing on error handling. then stop");
+        // The last value given wins, and a parameter may be used more than once
+        let expanded = expand(&[("twice", "{{x}} and {{x}}")], "@tpl:twice x=a x=\"b c\"", true);
+        assert_eq!(expanded.unwrap(), "b c and b c");
+        // Unknown parameters are ignored
+        assert_eq!(expand(&[("plain", "No slots")], "@tpl:plain extra=1", true).unwrap(), "No slots");
+    }
+
+    #[test]
+    fn missing_parameters_are_a_clear_error() {
+        let error = expand(&[("review", REVIEW)], "@tpl:review file=a.rs", true).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.to_string(),
+            "Template 'review' is missing parameter focus (usage: @tpl:review file=... focus=...)"
+        );
+        let error = expand(&[("review", REVIEW)], "@tpl:review", true).unwrap_err();
+        assert!(error.to_string().contains("missing parameters file, focus"), "{}", error);
+        let error = expand(&[], "@tpl:nope", true).unwrap_err();
+        assert!(error.to_string().starts_with("Template 'nope' not found"), "{}", error);
+    }
+
+    #[test]
+    fn earlier_prompts_only_warn() {
+        let text = "Before @tpl:review file=a.rs after";
+        assert!(expand(&[("review", REVIEW)], text, true).is_err());
+        assert_eq!(expand(&[("review", REVIEW)], text, false).unwrap(), text);
+        assert_eq!(expand(&[], "@tpl:nope", false).unwrap(), "@tpl:nope");
+    }
+}
@@ -0,0 +1,296 @@
+// The `@url:` placeholder. Pages are fetched before placeholders are expanded (the
+// HTTP client is async, the expander isn't) and converted to text: HTML loses its
+// navigation, headers and footers, JSON is pretty-printed. Each response is cached
+// on disk by URL with its ETag/Last-Modified, so an unchanged page is revalidated
+// rather than downloaded again, and a cached copy stands in when a fetch fails.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, USER_AGENT};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const URL_PATTERN: &str = r"@url\s*:(https?://\S+)";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+// Column width HTML is wrapped to
+const TEXT_WIDTH: usize = 100;
+// Page furniture left out of converted HTML
+const CHROME_TAGS: [&str; 8] = ["nav", "header", "footer", "aside", "script", "style", "noscript", "form"];
+
+// A fetched page, as text
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub text: String,
+    // Fence info string, e.g. "json"
+    pub language: &'static str,
+}
+
+// A response as cached on disk, before conversion
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    content_type: String,
+    body: String,
+}
+
+// Fetch every page `@url:` placeholders in `texts` refer to, once each
+pub async fn fetch_pages<'a>(client: &Client, texts: impl Iterator<Item = &'a str>) -> HashMap<String, Result<Page, String>> {
+    let re = Regex::new(URL_PATTERN).unwrap();
+    let mut pages = HashMap::new();
+    for text in texts {
+        for cap in re.captures_iter(text) {
+            let url = &cap[1];
+            if !pages.contains_key(url) {
+                println!("Fetching {} for @url", url);
+                let page = fetch(client, url, &cache_path(url)).await;
+                pages.insert(url.to_string(), page.map_err(|e| e.to_string()));
+            }
+        }
+    }
+    pages
+}
+
+// Fetch a page, revalidating or falling back to the copy cached at `path`
+async fn fetch(client: &Client, url: &str, path: &Path) -> io::Result<Page> {
+    let cached: Option<CacheEntry> = fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+
+    let mut request = client
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .header(USER_AGENT, concat!("gchat/", env!("CARGO_PKG_VERSION")));
+    if let Some(entry) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let entry = match (request.send().await, cached) {
+        (Ok(response), Some(cached)) if response.status() == StatusCode::NOT_MODIFIED => {
+            println!("  {} is unchanged, using the cached copy", url);
+            cached
+        }
+        (Ok(response), _) if response.status().is_success() => {
+            let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+            let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+            let content_type = header(CONTENT_TYPE).unwrap_or_default();
+            if language_of(&content_type).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported content type '{}'", content_type),
+                ));
+            }
+            let entry = CacheEntry {
+                url: url.to_string(),
+                etag,
+                last_modified,
+                content_type,
+                body: response.text().await.map_err(io::Error::other)?,
+            };
+            if let Err(e) = save(path, &entry) {
+                println!("Warning: Failed to cache {}: {}", url, e);
+            }
+            entry
+        }
+        (Ok(response), _) => return Err(io::Error::other(format!("HTTP {}", response.status()))),
+        (Err(e), Some(cached)) => {
+            println!("Warning: Failed to fetch {} ({}), using the cached copy", url, e);
+            cached
+        }
+        (Err(e), None) => return Err(io::Error::other(e)),
+    };
+    convert(&entry)
+}
+
+fn convert(entry: &CacheEntry) -> io::Result<Page> {
+    let language = language_of(&entry.content_type).unwrap_or("");
+    let text = match language {
+        "html" => html_to_text(&entry.body)?,
+        // Minified JSON is hard to read, and reformatting it costs little
+        "json" => match serde_json::from_str::<serde_json::Value>(&entry.body) {
+            Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_else(|_| entry.body.clone()),
+            Err(_) => entry.body.clone(),
+        },
+        _ => entry.body.clone(),
+    };
+    Ok(Page {
+        text,
+        // Converted HTML is plain text
+        language: if language == "html" { "" } else { language },
+    })
+}
+
+// The kind of text a content type holds, or `None` if it isn't text
+fn language_of(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    match mime.as_str() {
+        "text/html" | "application/xhtml+xml" => Some("html"),
+        "application/json" => Some("json"),
+        "text/markdown" | "text/x-markdown" => Some("markdown"),
+        "text/xml" | "application/xml" => Some("xml"),
+        "text/css" => Some("css"),
+        "text/javascript" | "application/javascript" => Some("javascript"),
+        // No content type is most likely plain text
+        "" | "text/plain" => Some(""),
+        _ if mime.ends_with("+json") => Some("json"),
+        _ if mime.ends_with("+xml") => Some("xml"),
+        _ if mime.starts_with("text/") => Some(""),
+        _ => None,
+    }
+}
+
+// Readable text from a page: just its `<main>` or `<article>` if it marks one,
+// without navigation and other furniture
+fn html_to_text(html: &str) -> io::Result<String> {
+    let main = Regex::new(r"(?is)<main\b.*?</main\s*>|<article\b.*?</article\s*>").unwrap();
+    let mut html = main.find(html).map_or(html, |m| m.as_str()).to_string();
+    for tag in CHROME_TAGS {
+        let re = Regex::new(&format!(r"(?is)<{}\b.*?</{}\s*>", tag, tag)).unwrap();
+        html = re.replace_all(&html, "").into_owned();
+    }
+    let text = html2text::from_read(html.as_bytes(), TEXT_WIDTH)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to convert HTML: {}", e)))?;
+    let blank_lines = Regex::new(r"\n{3,}").unwrap();
+    Ok(blank_lines.replace_all(text.trim(), "\n\n").into_owned())
+}
+
+fn cache_path(url: &str) -> PathBuf {
+    let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("gchat/urls")
+        .join(format!("{}.json", key))
+}
+
+fn save(path: &Path, entry: &CacheEntry) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(entry).map_err(io::Error::other)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve one canned response to each connection in turn, returning the server's
+    // base URL and a handle yielding the request heads it received
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut head = Vec::new();
+                    let mut byte = [0u8];
+                    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                        head.push(byte[0]);
+                    }
+                    stream.write_all(response.as_bytes()).unwrap();
+                    String::from_utf8_lossy(&head).to_lowercase()
+                })
+                .collect()
+        });
+        (base, handle)
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        format!("{}\r\n{}", response, body)
+    }
+
+    fn client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    // A cache file of its own for each test
+    fn cache_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gchat-web-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    const PAGE: &str = "<html><body><nav>Home | Docs</nav><main><h1>Title</h1><p>Body text.</p></main><footer>Footer</footer></body></html>";
+
+    #[tokio::test]
+    async fn html_is_converted_and_revalidated_by_etag() {
+        let html = [("Content-Type", "text/html; charset=utf-8"), ("ETag", "\"v1\"")];
+        let (base, server) = serve(vec![response("200 OK", &html, PAGE), response("304 Not Modified", &[], "")]);
+        let cache = cache_file("etag");
+        let url = format!("{}/page", base);
+
+        let first = fetch(&client(), &url, &cache).await.unwrap();
+        assert!(first.text.contains("Title") && first.text.contains("Body text."));
+        assert!(!first.text.contains("Home") && !first.text.contains("Footer"));
+        assert_eq!(first.language, "");
+
+        let second = fetch(&client(), &url, &cache).await.unwrap();
+        assert_eq!(second.text, first.text);
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        fs::remove_file(&cache).unwrap();
+    }
+
+    #[tokio::test]
+    async fn content_types() {
+        let (base, server) = serve(vec![
+            response("200 OK", &[("Content-Type", "application/json")], r#"{"a":[1,2]}"#),
+            response("200 OK", &[("Content-Type", "text/plain")], "just text\n"),
+            response("200 OK", &[("Content-Type", "image/png")], "PNG"),
+            response("404 Not Found", &[("Content-Type", "text/plain")], "missing"),
+        ]);
+        let cache = cache_file("types");
+
+        let json = fetch(&client(), &format!("{}/a.json", base), &cache).await.unwrap();
+        assert_eq!(json.language, "json");
+        assert_eq!(json.text, "{\n  \"a\": [\n    1,\n    2\n  ]\n}");
+        let text = fetch(&client(), &format!("{}/a.txt", base), &cache).await.unwrap();
+        assert_eq!((text.text.as_str(), text.language), ("just text\n", ""));
+        fs::remove_file(&cache).unwrap();
+
+        let image = fetch(&client(), &format!("{}/a.png", base), &cache).await.unwrap_err();
+        assert!(image.to_string().contains("Unsupported content type 'image/png'"));
+        let missing = fetch(&client(), &format!("{}/gone", base), &cache).await.unwrap_err();
+        assert!(missing.to_string().contains("404"));
+        assert!(!cache.exists());
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cached_copy_stands_in_when_the_fetch_fails() {
+        let (base, server) = serve(vec![response("200 OK", &[("Content-Type", "text/markdown")], "# Notes\n")]);
+        let cache = cache_file("fallback");
+        let url = format!("{}/notes.md", base);
+
+        let fresh = fetch(&client(), &url, &cache).await.unwrap();
+        assert_eq!((fresh.text.as_str(), fresh.language), ("# Notes\n", "markdown"));
+        // The server has gone away
+        server.join().unwrap();
+        let cached = fetch(&client(), &url, &cache).await.unwrap();
+        assert_eq!(cached.text, fresh.text);
+
+        fs::remove_file(&cache).unwrap();
+        assert!(fetch(&client(), &url, &cache).await.is_err());
+    }
+}